@@ -6,6 +6,8 @@ set -euxo pipefail
 
 (cd ppproto; cargo build --no-default-features)
 (cd ppproto; cargo build --no-default-features --features log)
+(cd ppproto; cargo build --no-default-features --features rustls)
+(cd ppproto; cargo test --features rustls)
 
 # embedded
 (cd ppproto; cargo build --target thumbv7em-none-eabi --no-default-features)
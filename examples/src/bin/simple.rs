@@ -5,7 +5,7 @@ use clap::Clap;
 use std::io::{Read, Write};
 use std::path::Path;
 
-use ppproto::{Config, PPPoS, PPPoSAction};
+use ppproto::{Config, Instant, PPPoS, PPPoSAction};
 use serial_port::SerialPort;
 
 #[derive(Clap)]
@@ -23,6 +23,7 @@ fn main() {
     let config = Config {
         username: b"myuser",
         password: b"mypass",
+        ..Default::default()
     };
     let mut ppp = PPPoS::new(config);
 
@@ -37,7 +38,8 @@ fn main() {
     let mut data: &[u8] = &[];
     loop {
         // Poll the ppp
-        match ppp.poll(&mut tx_buf) {
+        let now = Instant::now();
+        match ppp.poll_timed(now, &mut tx_buf) {
             PPPoSAction::None => {}
             PPPoSAction::Transmit(n) => port.write_all(&tx_buf[..n]).unwrap(),
             PPPoSAction::Received(rx_buf, range) => {
@@ -84,8 +86,8 @@ fn main() {
             }
         }
 
-        // If we have no data, read some.
-        if data.len() == 0 {
+        // If we have no data, wait for some or until the next timer expires.
+        if data.len() == 0 && port.wait(ppp.poll_delay(now).map(Into::into)).unwrap() {
             let n = port.read(&mut read_buf).unwrap();
             data = &read_buf[..n];
         }
@@ -76,7 +76,7 @@ impl<'a> Device<'a> for PPPDevice {
         let mut data: &[u8] = &[];
         loop {
             // Poll the ppp
-            match self.ppp.poll(&mut tx_buf) {
+            match self.ppp.poll_timed(ppproto::Instant::now(), &mut tx_buf) {
                 PPPoSAction::None => {}
                 PPPoSAction::Transmit(n) => self.port.write_all(&tx_buf[..n]).unwrap(),
                 PPPoSAction::Received(buf, range) => {
@@ -122,7 +122,7 @@ impl<'a> Device<'a> for PPPDevice {
     /// Get a description of device capabilities.
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps: DeviceCapabilities = Default::default();
-        caps.max_transmission_unit = 1500;
+        caps.max_transmission_unit = self.ppp.status().mtu.unwrap_or(1500) as usize;
         caps.medium = Medium::Ip;
         caps
     }
@@ -180,6 +180,7 @@ fn main() {
     let config = Config {
         username: b"myuser",
         password: b"mypass",
+        ..Default::default()
     };
     let mut ppp = PPPoS::new(config);
 
@@ -376,6 +377,16 @@ fn main() {
             }
         }
 
-        phy_wait(fd, iface.poll_delay(&sockets, timestamp)).expect("wait error");
+        // Wake up for whichever comes first: smoltcp timers or PPP timers.
+        let ppp_delay = iface
+            .device()
+            .ppp
+            .poll_delay(ppproto::Instant::now())
+            .map(|d| Duration::from_millis(d.total_millis()));
+        let delay = match (iface.poll_delay(&sockets, timestamp), ppp_delay) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        phy_wait(fd, delay).expect("wait error");
     }
 }
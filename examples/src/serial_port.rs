@@ -1,8 +1,10 @@
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::time::Duration;
 
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::termios;
 use nix::Error;
 
@@ -46,6 +48,16 @@ impl SerialPort {
         fcntl(self.fd, FcntlArg::F_SETFL(f)).map_err(to_io_error)?;
         Ok(())
     }
+
+    /// Wait until there is data to read, or until the timeout expires.
+    ///
+    /// Returns true if there is data to read.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout = timeout.map(|t| t.as_millis() as i32).unwrap_or(-1);
+        let mut fds = [PollFd::new(self.fd, PollFlags::POLLIN)];
+        let n = poll(&mut fds, timeout).map_err(to_io_error)?;
+        Ok(n > 0)
+    }
 }
 
 impl AsRawFd for SerialPort {
@@ -0,0 +1,172 @@
+//! DES block cipher, FIPS 46-3. Only used to compute MS-CHAP responses.
+
+const IP: [u8; 64] = [
+    58, 50, 42, 34, 26, 18, 10, 2, 60, 52, 44, 36, 28, 20, 12, 4, //
+    62, 54, 46, 38, 30, 22, 14, 6, 64, 56, 48, 40, 32, 24, 16, 8, //
+    57, 49, 41, 33, 25, 17, 9, 1, 59, 51, 43, 35, 27, 19, 11, 3, //
+    61, 53, 45, 37, 29, 21, 13, 5, 63, 55, 47, 39, 31, 23, 15, 7,
+];
+
+const FP: [u8; 64] = [
+    40, 8, 48, 16, 56, 24, 64, 32, 39, 7, 47, 15, 55, 23, 63, 31, //
+    38, 6, 46, 14, 54, 22, 62, 30, 37, 5, 45, 13, 53, 21, 61, 29, //
+    36, 4, 44, 12, 52, 20, 60, 28, 35, 3, 43, 11, 51, 19, 59, 27, //
+    34, 2, 42, 10, 50, 18, 58, 26, 33, 1, 41, 9, 49, 17, 57, 25,
+];
+
+const E: [u8; 48] = [
+    32, 1, 2, 3, 4, 5, 4, 5, 6, 7, 8, 9, 8, 9, 10, 11, //
+    12, 13, 12, 13, 14, 15, 16, 17, 16, 17, 18, 19, 20, 21, 20, 21, //
+    22, 23, 24, 25, 24, 25, 26, 27, 28, 29, 28, 29, 30, 31, 32, 1,
+];
+
+const P: [u8; 32] = [
+    16, 7, 20, 21, 29, 12, 28, 17, 1, 15, 23, 26, 5, 18, 31, 10, //
+    2, 8, 24, 14, 32, 27, 3, 9, 19, 13, 30, 6, 22, 11, 4, 25,
+];
+
+const PC1: [u8; 56] = [
+    57, 49, 41, 33, 25, 17, 9, 1, 58, 50, 42, 34, 26, 18, //
+    10, 2, 59, 51, 43, 35, 27, 19, 11, 3, 60, 52, 44, 36, //
+    63, 55, 47, 39, 31, 23, 15, 7, 62, 54, 46, 38, 30, 22, //
+    14, 6, 61, 53, 45, 37, 29, 21, 13, 5, 28, 20, 12, 4,
+];
+
+const PC2: [u8; 48] = [
+    14, 17, 11, 24, 1, 5, 3, 28, 15, 6, 21, 10, //
+    23, 19, 12, 4, 26, 8, 16, 7, 27, 20, 13, 2, //
+    41, 52, 31, 37, 47, 55, 30, 40, 51, 45, 33, 48, //
+    44, 49, 39, 56, 34, 53, 46, 42, 50, 36, 29, 32,
+];
+
+const SHIFTS: [u32; 16] = [1, 1, 2, 2, 2, 2, 2, 2, 1, 2, 2, 2, 2, 2, 2, 1];
+
+/// S-boxes, indexed by row * 16 + column.
+const S: [[u8; 64]; 8] = [
+    [
+        14, 4, 13, 1, 2, 15, 11, 8, 3, 10, 6, 12, 5, 9, 0, 7, //
+        0, 15, 7, 4, 14, 2, 13, 1, 10, 6, 12, 11, 9, 5, 3, 8, //
+        4, 1, 14, 8, 13, 6, 2, 11, 15, 12, 9, 7, 3, 10, 5, 0, //
+        15, 12, 8, 2, 4, 9, 1, 7, 5, 11, 3, 14, 10, 0, 6, 13,
+    ],
+    [
+        15, 1, 8, 14, 6, 11, 3, 4, 9, 7, 2, 13, 12, 0, 5, 10, //
+        3, 13, 4, 7, 15, 2, 8, 14, 12, 0, 1, 10, 6, 9, 11, 5, //
+        0, 14, 7, 11, 10, 4, 13, 1, 5, 8, 12, 6, 9, 3, 2, 15, //
+        13, 8, 10, 1, 3, 15, 4, 2, 11, 6, 7, 12, 0, 5, 14, 9,
+    ],
+    [
+        10, 0, 9, 14, 6, 3, 15, 5, 1, 13, 12, 7, 11, 4, 2, 8, //
+        13, 7, 0, 9, 3, 4, 6, 10, 2, 8, 5, 14, 12, 11, 15, 1, //
+        13, 6, 4, 9, 8, 15, 3, 0, 11, 1, 2, 12, 5, 10, 14, 7, //
+        1, 10, 13, 0, 6, 9, 8, 7, 4, 15, 14, 3, 11, 5, 2, 12,
+    ],
+    [
+        7, 13, 14, 3, 0, 6, 9, 10, 1, 2, 8, 5, 11, 12, 4, 15, //
+        13, 8, 11, 5, 6, 15, 0, 3, 4, 7, 2, 12, 1, 10, 14, 9, //
+        10, 6, 9, 0, 12, 11, 7, 13, 15, 1, 3, 14, 5, 2, 8, 4, //
+        3, 15, 0, 6, 10, 1, 13, 8, 9, 4, 5, 11, 12, 7, 2, 14,
+    ],
+    [
+        2, 12, 4, 1, 7, 10, 11, 6, 8, 5, 3, 15, 13, 0, 14, 9, //
+        14, 11, 2, 12, 4, 7, 13, 1, 5, 0, 15, 10, 3, 9, 8, 6, //
+        4, 2, 1, 11, 10, 13, 7, 8, 15, 9, 12, 5, 6, 3, 0, 14, //
+        11, 8, 12, 7, 1, 14, 2, 13, 6, 15, 0, 9, 10, 4, 5, 3,
+    ],
+    [
+        12, 1, 10, 15, 9, 2, 6, 8, 0, 13, 3, 4, 14, 7, 5, 11, //
+        10, 15, 4, 2, 7, 12, 9, 5, 6, 1, 13, 14, 0, 11, 3, 8, //
+        9, 14, 15, 5, 2, 8, 12, 3, 7, 0, 4, 10, 1, 13, 11, 6, //
+        4, 3, 2, 12, 9, 5, 15, 10, 11, 14, 1, 7, 6, 0, 8, 13,
+    ],
+    [
+        4, 11, 2, 14, 15, 0, 8, 13, 3, 12, 9, 7, 5, 10, 6, 1, //
+        13, 0, 11, 7, 4, 9, 1, 10, 14, 3, 5, 12, 2, 15, 8, 6, //
+        1, 4, 11, 13, 12, 3, 7, 14, 10, 15, 6, 8, 0, 5, 9, 2, //
+        6, 11, 13, 8, 1, 4, 10, 7, 9, 5, 0, 15, 14, 2, 3, 12,
+    ],
+    [
+        13, 2, 8, 4, 6, 15, 11, 1, 10, 9, 3, 14, 5, 0, 12, 7, //
+        1, 15, 13, 8, 10, 3, 7, 4, 12, 5, 6, 11, 0, 14, 9, 2, //
+        7, 11, 4, 1, 9, 12, 14, 2, 0, 6, 10, 13, 15, 3, 5, 8, //
+        2, 1, 14, 7, 4, 10, 8, 13, 15, 12, 9, 0, 3, 5, 6, 11,
+    ],
+];
+
+/// Select the bits of `input`, `bits` wide, listed in `table`. Bits are numbered from 1,
+/// most significant first.
+fn permute(input: u64, bits: u32, table: &[u8]) -> u64 {
+    table
+        .iter()
+        .fold(0, |out, &t| out << 1 | (input >> (bits - t as u32)) & 1)
+}
+
+/// Encrypt one block with a 56-bit key, given without the parity bits.
+pub(crate) fn des_encrypt(key: &[u8; 7], block: &[u8; 8]) -> [u8; 8] {
+    let mut key56 = [0; 8];
+    key56[1..].copy_from_slice(key);
+    // Spread the key in 8 bytes of 7 bits, PC1 skips the unused lowest bit of each.
+    let key56 = u64::from_be_bytes(key56);
+    let key64 = (0..8).fold(0, |k, i| k << 8 | ((key56 >> (49 - 7 * i)) & 0x7f) << 1);
+
+    let cd = permute(key64, 64, &PC1);
+    let (mut c, mut d) = ((cd >> 28) as u32, (cd & 0x0fff_ffff) as u32);
+
+    let data = permute(u64::from_be_bytes(*block), 64, &IP);
+    let (mut l, mut r) = ((data >> 32) as u32, data as u32);
+    for &shift in SHIFTS.iter() {
+        c = (c << shift | c >> (28 - shift)) & 0x0fff_ffff;
+        d = (d << shift | d >> (28 - shift)) & 0x0fff_ffff;
+        let subkey = permute((c as u64) << 28 | d as u64, 56, &PC2);
+
+        let x = permute(r as u64, 32, &E) ^ subkey;
+        let mut s = 0u64;
+        for (i, sbox) in S.iter().enumerate() {
+            let six = (x >> (42 - 6 * i)) & 0x3f;
+            let row = (six >> 4 & 2) | (six & 1);
+            let col = (six >> 1) & 0xf;
+            s = s << 4 | sbox[(row * 16 + col) as usize] as u64;
+        }
+        let f = permute(s, 32, &P) as u32;
+
+        let next = l ^ f;
+        l = r;
+        r = next;
+    }
+
+    let out = permute((r as u64) << 32 | l as u64, 64, &FP);
+    out.to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fips_vectors() {
+        // Keys without their parity bits, 7 bits from each byte of the 64-bit key.
+        let vectors = [
+            // 133457799BBCDFF1, the worked example from "The DES Algorithm Illustrated".
+            (
+                [0x12, 0x69, 0x5b, 0xc9, 0xb7, 0xb7, 0xf8],
+                [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+                [0x85, 0xe8, 0x13, 0x54, 0x0f, 0x0a, 0xb4, 0x05],
+            ),
+            // 0E329232EA6D0D73
+            (
+                [0x0e, 0x66, 0x49, 0x9e, 0xad, 0x83, 0x39],
+                [0x87, 0x87, 0x87, 0x87, 0x87, 0x87, 0x87, 0x87],
+                [0x00; 8],
+            ),
+            // 0101010101010101, from the variable plaintext known answer test of NIST SP 800-17.
+            (
+                [0x00; 7],
+                [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+                [0x95, 0xf8, 0xa5, 0xe5, 0xdd, 0x31, 0xd9, 0x00],
+            ),
+        ];
+        for (key, plain, cipher) in vectors.iter() {
+            assert_eq!(des_encrypt(key, plain), *cipher);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use super::Blocks;
+
+/// MD4 message digest, RFC 1320. Only used for the MS-CHAP password hash.
+pub(crate) struct Md4 {
+    state: [u32; 4],
+    blocks: Blocks,
+}
+
+/// Message word used by each step of rounds 2 and 3.
+const ROUND2: [usize; 16] = [0, 4, 8, 12, 1, 5, 9, 13, 2, 6, 10, 14, 3, 7, 11, 15];
+const ROUND3: [usize; 16] = [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15];
+
+impl Md4 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            blocks: Blocks::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |block| compress(state, block));
+    }
+
+    pub fn finalize(mut self) -> [u8; 16] {
+        let state = &mut self.state;
+        self.blocks.finish(false, |block| compress(state, block));
+
+        let mut out = [0; 16];
+        for (chunk, word) in out.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn compress(state: &mut [u32; 4], block: &[u8; 64]) {
+    let mut m = [0u32; 16];
+    for (word, chunk) in m.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..48 {
+        let (f, g, k, s) = match i / 16 {
+            0 => ((b & c) | (!b & d), i, 0, [3, 7, 11, 19]),
+            1 => (
+                (b & c) | (b & d) | (c & d),
+                ROUND2[i % 16],
+                0x5a827999,
+                [3, 5, 9, 13],
+            ),
+            _ => (b ^ c ^ d, ROUND3[i % 16], 0x6ed9eba1, [3, 9, 11, 15]),
+        };
+        let x = a
+            .wrapping_add(f)
+            .wrapping_add(m[g])
+            .wrapping_add(k)
+            .rotate_left(s[i % 4]);
+        a = d;
+        d = c;
+        c = b;
+        b = x;
+    }
+
+    for (s, x) in state.iter_mut().zip([a, b, c, d].iter()) {
+        *s = s.wrapping_add(*x);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> [u8; 16] {
+        let mut out = [0; 16];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..][..2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn rfc1320_test_suite() {
+        let vectors: [(&[u8], &str); 7] = [
+            (b"", "31d6cfe0d16ae931b73c59d7e0c089c0"),
+            (b"a", "bde52cb31de33e46245e05fbdbd6fb24"),
+            (b"abc", "a448017aaf21d8525fc10ae87aa6729d"),
+            (b"message digest", "d9130a8164549fe818874806e1c7014b"),
+            (
+                b"abcdefghijklmnopqrstuvwxyz",
+                "d79e1c308aa5bbcdeea8ed63df412da9",
+            ),
+            (
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "043f8582f241db351ce627e153e7f0e4",
+            ),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+            ),
+        ];
+        for (data, digest) in vectors.iter() {
+            let mut md4 = Md4::new();
+            md4.update(data);
+            assert_eq!(md4.finalize(), hex(digest));
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Cryptographic primitives needed by the authentication protocols.
+//!
+//! These are small implementations for `no_std` targets, optimized for size rather than
+//! speed. Authentication processes a few bytes per link establishment.
+
+mod des;
+mod md4;
+mod md5;
+mod sha1;
+
+pub(crate) use self::des::des_encrypt;
+pub(crate) use self::md4::Md4;
+pub(crate) use self::md5::Md5;
+pub(crate) use self::sha1::Sha1;
+
+/// Splits the input of a hash function in 64-byte blocks, and pads the last one with
+/// the message length in bits, as MD4, MD5 and SHA-1 do.
+struct Blocks {
+    buf: [u8; 64],
+    len: usize,
+    total: u64,
+}
+
+impl Blocks {
+    fn new() -> Self {
+        Self {
+            buf: [0; 64],
+            len: 0,
+            total: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut f: impl FnMut(&[u8; 64])) {
+        self.total = self.total.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let n = data.len().min(64 - self.len);
+            self.buf[self.len..][..n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len == 64 {
+                f(&self.buf);
+                self.len = 0;
+            }
+        }
+    }
+
+    /// Pad the message and process the last blocks. The length is big endian for SHA-1,
+    /// little endian for MD4 and MD5.
+    fn finish(&mut self, big_endian: bool, mut f: impl FnMut(&[u8; 64])) {
+        let bits = self.total.wrapping_mul(8);
+        self.update(&[0x80], &mut f);
+        while self.len != 56 {
+            self.update(&[0], &mut f);
+        }
+        if big_endian {
+            self.update(&bits.to_be_bytes(), f);
+        } else {
+            self.update(&bits.to_le_bytes(), f);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use super::Blocks;
+
+/// SHA-1 message digest, RFC 3174.
+pub(crate) struct Sha1 {
+    state: [u32; 5],
+    blocks: Blocks,
+}
+
+impl Sha1 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            blocks: Blocks::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.blocks.update(data, |block| compress(state, block));
+    }
+
+    pub fn finalize(mut self) -> [u8; 20] {
+        let state = &mut self.state;
+        self.blocks.finish(true, |block| compress(state, block));
+
+        let mut out = [0; 20];
+        for (chunk, word) in out.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn compress(state: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (word, chunk) in w.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, &w) in w.iter().enumerate() {
+        let (f, k) = match i / 20 {
+            0 => ((b & c) | (!b & d), 0x5a827999),
+            1 => (b ^ c ^ d, 0x6ed9eba1),
+            2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let x = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = x;
+    }
+
+    for (s, x) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+        *s = s.wrapping_add(*x);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> [u8; 20] {
+        let mut out = [0; 20];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..][..2], 16).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn rfc3174_test_suite() {
+        // Input, repeat count and digest, from the test driver of section 7.3.
+        let vectors: [(&[u8], usize, &str); 4] = [
+            (b"abc", 1, "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                1,
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+            (b"a", 1000000, "34aa973cd4c4daa4f61eeb2bdbad27316534016f"),
+            (
+                b"0123456701234567012345670123456701234567012345670123456701234567",
+                10,
+                "dea356a2cddd90c7a7ecedc5ebb563934f460452",
+            ),
+        ];
+        for (data, count, digest) in vectors.iter() {
+            let mut sha1 = Sha1::new();
+            for _ in 0..*count {
+                sha1.update(data);
+            }
+            assert_eq!(sha1.finalize(), hex(digest));
+        }
+    }
+}
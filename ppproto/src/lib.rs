@@ -3,12 +3,21 @@
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+mod crypto;
 mod ppp;
 pub mod pppos;
+mod time;
 mod wire;
 
-pub use ppp::{Config, Phase, Status};
-pub use pppos::{BufferFullError, PPPoS, PPPoSAction};
+#[cfg(feature = "rustls")]
+pub use ppp::RustlsBackend;
+pub use ppp::{
+    Config, Counters, EapError, EapMethod, EapTls, Failure, Identification, LinkQuality,
+    MsChapFailure, Phase, Rng, Status, TlsBackend,
+};
+pub use pppos::{BufferFullError, PPPoS, PPPoSAction, SendError};
+pub use time::{Duration, Instant};
+pub use wire::MalformedError;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
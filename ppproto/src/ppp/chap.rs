@@ -0,0 +1,317 @@
+use crate::fmt::{assert, *};
+
+use super::mschapv2::{self, MsChapFailure};
+use super::option_fsm::code_reject_data;
+use super::timer::Timer;
+use super::{Config, Rng};
+use crate::crypto::Md5;
+use crate::time::{Duration, Instant};
+use crate::wire::{
+    parse_header, Code, MalformedError, PPPPayload, Packet, Payload, ProtocolType,
+    MAX_CONTROL_PACKET_LEN,
+};
+
+// CHAP codes, RFC 1994 section 4. They have the same values as the LCP ones.
+const CHALLENGE: Code = Code::ConfigureReq;
+const RESPONSE: Code = Code::ConfigureAck;
+const SUCCESS: Code = Code::ConfigureNack;
+const FAILURE: Code = Code::ConfigureRej;
+
+/// Length of an MD5 Response value.
+const MD5_LEN: usize = 16;
+/// Length of an MS-CHAPv2 Response value: Peer-Challenge, reserved, NT-Response and flags.
+const MSCHAPV2_LEN: usize = 16 + 8 + 24 + 1;
+
+/// Returns true if `name` fits in a Response with `algorithm`: a control packet minus the
+/// protocol, header, Value-Size and value. LCP refuses algorithms our name doesn't fit in.
+pub(crate) fn name_fits(algorithm: Algorithm, name: &[u8]) -> bool {
+    let value_len = match algorithm {
+        Algorithm::Md5 => MD5_LEN,
+        Algorithm::MsChapV2 => MSCHAPV2_LEN,
+    };
+    name.len() <= MAX_CONTROL_PACKET_LEN - 2 - 4 - 1 - value_len
+}
+
+/// MD5 Response value: MD5 of the Identifier, the secret and the Challenge. Also used by
+/// EAP MD5-Challenge.
+pub(crate) fn md5_response(id: u8, secret: &[u8], challenge: &[u8]) -> [u8; MD5_LEN] {
+    let mut md5 = Md5::new();
+    md5.update(&[id]);
+    md5.update(secret);
+    md5.update(challenge);
+    md5.finalize()
+}
+
+/// CHAP Algorithm, negotiated in the LCP Authentication-Protocol option.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Algorithm {
+    Md5,
+    /// MS-CHAPv2, RFC 2759.
+    MsChapV2,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Closed,
+    /// Waiting for the peer's Challenge.
+    Listen,
+    /// Response sent, waiting for Success or Failure.
+    RespSent,
+    Opened,
+    /// The peer sent a Failure.
+    Failed,
+    /// The MS-CHAPv2 Success from the peer didn't prove it knows the password.
+    BadSuccess,
+    /// No Challenge or Success from the peer in time.
+    TimedOut,
+}
+
+/// Challenge-Handshake Authentication Protocol, RFC 1994, with MD5 or MS-CHAPv2. We only
+/// authenticate ourselves to the peer.
+pub struct CHAP<'a> {
+    state: State,
+    id: u8,
+    algorithm: Algorithm,
+
+    username: &'a [u8],
+    password: &'a [u8],
+
+    /// Identifier, value and Challenge of the last Response.
+    resp_id: u8,
+    resp: [u8; MSCHAPV2_LEN],
+    resp_len: usize,
+    challenge: [u8; 16],
+
+    ms_chap_failure: Option<MsChapFailure>,
+    master_key: Option<[u8; 16]>,
+
+    timer: Timer,
+    /// How long to wait for the peer to finish authenticating us.
+    timeout: Duration,
+}
+
+impl<'a> CHAP<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        // The peer retransmits its Challenge as we would retransmit an Authenticate-Request.
+        let timeout = config.restart_interval.total_millis() * config.max_configure as u64;
+        Self {
+            state: State::Closed,
+            id: 1,
+            algorithm: Algorithm::Md5,
+            username: config.username,
+            password: config.password,
+
+            resp_id: 0,
+            resp: [0; MSCHAPV2_LEN],
+            resp_len: 0,
+            challenge: [0; 16],
+
+            ms_chap_failure: None,
+            master_key: None,
+
+            timer: Timer::Stopped,
+            timeout: Duration::from_millis(timeout),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Failure reported by the peer in its last MS-CHAPv2 Failure packet.
+    pub fn ms_chap_failure(&self) -> Option<MsChapFailure> {
+        self.ms_chap_failure
+    }
+
+    /// MPPE master key, RFC 3079 section 3.4. Only once MS-CHAPv2 succeeded.
+    pub fn master_key(&self) -> Option<[u8; 16]> {
+        self.master_key
+    }
+
+    /// Start waiting for the peer's Challenge.
+    pub fn open(&mut self, algorithm: Algorithm) {
+        assert!(self.state == State::Closed);
+        self.state = State::Listen;
+        self.algorithm = algorithm;
+        self.ms_chap_failure = None;
+        self.timer.start();
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+        self.master_key = None;
+        self.timer.stop();
+    }
+
+    /// Give up if the peer doesn't authenticate us in time.
+    pub fn poll(&mut self, now: Instant) {
+        if !self.timer.poll(now, self.timeout)
+            || !matches!(self.state, State::Listen | State::RespSent)
+        {
+            return;
+        }
+
+        info!("CHAP: peer didn't authenticate us, giving up");
+        self.state = State::TimedOut;
+    }
+
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        rng: Option<&mut dyn Rng>,
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<(), MalformedError> {
+        let (code, id, data) = parse_header(pkt).ok_or(MalformedError::TooShort)?;
+        let len = 6 + data.len();
+
+        info!("CHAP: rx {:?}", code);
+        let old_state = self.state;
+        match (code, self.state) {
+            (CHALLENGE, State::Closed) => {}
+            (CHALLENGE, _) => {
+                if data.is_empty() || data.len() < 1 + data[0] as usize {
+                    return Err(MalformedError::TooShort);
+                }
+                let challenge = &data[1..][..data[0] as usize];
+                match (self.algorithm, rng) {
+                    (Algorithm::Md5, _) => self.respond_md5(id, challenge),
+                    (Algorithm::MsChapV2, Some(rng)) => {
+                        if challenge.len() != 16 {
+                            return Err(MalformedError::BadLength);
+                        }
+                        self.respond_ms_chap_v2(id, challenge, rng);
+                    }
+                    // LCP only accepts MS-CHAPv2 with a random source.
+                    (Algorithm::MsChapV2, None) => return Ok(()),
+                }
+                // A Challenge while Opened re-authenticates the link, which stays up
+                // unless the peer sends a Failure.
+                if self.state != State::Opened {
+                    self.state = State::RespSent;
+                }
+                tx(self.send_response());
+            }
+            (SUCCESS, State::RespSent) | (SUCCESS, State::Opened) if id == self.resp_id => {
+                self.state = if self.check_success(data) {
+                    State::Opened
+                } else {
+                    info!("CHAP: peer didn't prove it knows the password");
+                    State::BadSuccess
+                };
+                self.timer.stop();
+            }
+            (FAILURE, State::RespSent) | (FAILURE, State::Opened) if id == self.resp_id => {
+                info!("CHAP: peer refused our credentials");
+                if self.algorithm == Algorithm::MsChapV2 {
+                    let failure = MsChapFailure::parse(data);
+                    info!("CHAP: {:?}", failure);
+                    self.ms_chap_failure = Some(failure);
+                }
+                self.state = State::Failed;
+                self.timer.stop();
+            }
+            // We don't authenticate the peer, so Responses are ignored, as are stale
+            // Success and Failure packets.
+            (RESPONSE, _) | (SUCCESS, _) | (FAILURE, _) => {}
+            // Never answered with a Code-Reject, or two implementations would reject
+            // each other forever.
+            (Code::CodeRej, _) => info!("CHAP: peer rejected a code"),
+            _ => {
+                info!("CHAP: rejecting unknown code {:?}", pkt[2]);
+                tx(self.send_code_reject(&mut pkt[..len]))
+            }
+        }
+
+        if old_state != self.state {
+            info!("CHAP: state {:?} -> {:?}", old_state, self.state);
+        }
+        Ok(())
+    }
+
+    /// When we give up waiting for the peer to authenticate us.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer.poll_at()
+    }
+
+    fn respond_md5(&mut self, id: u8, challenge: &[u8]) {
+        self.resp[..MD5_LEN].copy_from_slice(&md5_response(id, self.password, challenge));
+        self.resp_len = MD5_LEN;
+        self.resp_id = id;
+    }
+
+    /// Compute the MS-CHAPv2 Response value, with a new random Peer-Challenge.
+    fn respond_ms_chap_v2(&mut self, id: u8, challenge: &[u8], rng: &mut dyn Rng) {
+        self.challenge.copy_from_slice(challenge);
+        let mut peer_challenge = [0; 16];
+        for chunk in peer_challenge.chunks_mut(4) {
+            chunk.copy_from_slice(&rng.next_u32().to_be_bytes());
+        }
+        let nt_response = mschapv2::nt_response(
+            &self.challenge,
+            &peer_challenge,
+            self.username,
+            self.password,
+        );
+
+        self.resp = [0; MSCHAPV2_LEN];
+        self.resp[..16].copy_from_slice(&peer_challenge);
+        self.resp[24..48].copy_from_slice(&nt_response);
+        self.resp_len = MSCHAPV2_LEN;
+        self.resp_id = id;
+    }
+
+    /// Check the message of a Success packet. With MS-CHAPv2 it must carry the
+    /// authenticator response, which proves the peer knows the password.
+    fn check_success(&mut self, msg: &[u8]) -> bool {
+        if self.algorithm != Algorithm::MsChapV2 {
+            return true;
+        }
+        let mut peer_challenge = [0; 16];
+        peer_challenge.copy_from_slice(&self.resp[..16]);
+        let mut nt_response = [0; 24];
+        nt_response.copy_from_slice(&self.resp[24..48]);
+
+        let expected = mschapv2::authenticator_response(
+            &self.challenge,
+            &peer_challenge,
+            &nt_response,
+            self.username,
+            self.password,
+        );
+        if !mschapv2::check_success(msg, &expected) {
+            return false;
+        }
+        self.master_key = Some(mschapv2::master_key(&nt_response, self.password));
+        true
+    }
+
+    fn next_id(&mut self) -> u8 {
+        self.id = self.id.wrapping_add(1);
+        self.id
+    }
+
+    fn send_code_reject<'p>(&mut self, pkt: &'p mut [u8]) -> Packet<'p> {
+        Packet {
+            proto: ProtocolType::CHAP,
+            payload: Payload::PPP(
+                Code::CodeRej,
+                self.next_id(),
+                PPPPayload::Raw(code_reject_data(pkt)),
+            ),
+        }
+    }
+
+    fn send_response(&self) -> Packet<'_> {
+        info!("CHAP: tx Response id {:?}", self.resp_id);
+        Packet {
+            proto: ProtocolType::CHAP,
+            payload: Payload::PPP(
+                RESPONSE,
+                self.resp_id,
+                PPPPayload::CHAP(&self.resp[..self.resp_len], self.username),
+            ),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use super::{EapError, EapMethod};
+use crate::ppp::chap::md5_response;
+
+/// MD5-Challenge method Type.
+pub(crate) const MD5_CHALLENGE: u8 = 4;
+
+/// MD5-Challenge, RFC 3748 section 5.4. Same as CHAP with MD5.
+pub(crate) struct Md5Challenge<'a> {
+    username: &'a [u8],
+    password: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Md5Challenge<'a> {
+    pub fn new(username: &'a [u8], password: &'a [u8]) -> Self {
+        Self {
+            username,
+            password,
+            done: false,
+        }
+    }
+}
+
+impl<'a> EapMethod for Md5Challenge<'a> {
+    fn method_type(&self) -> u8 {
+        MD5_CHALLENGE
+    }
+
+    fn start(&mut self) {
+        self.done = false;
+    }
+
+    fn process(&mut self, id: u8, req: &[u8], resp: &mut [u8]) -> Result<usize, EapError> {
+        if req.is_empty() || req.len() < 1 + req[0] as usize {
+            return Err(EapError::Malformed);
+        }
+        let challenge = &req[1..][..req[0] as usize];
+        let value = md5_response(id, self.password, challenge);
+
+        let name_len = self.username.len().min(resp.len() - 1 - value.len());
+        resp[0] = value.len() as u8;
+        resp[1..][..value.len()].copy_from_slice(&value);
+        resp[1 + value.len()..][..name_len].copy_from_slice(&self.username[..name_len]);
+        self.done = true;
+        Ok(1 + value.len() + name_len)
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
@@ -0,0 +1,612 @@
+mod md5;
+#[cfg(feature = "rustls")]
+mod rustls_backend;
+mod tls;
+
+use crate::fmt::*;
+use core::ops::{Deref, DerefMut};
+
+use self::md5::{Md5Challenge, MD5_CHALLENGE};
+use super::timer::Timer;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{
+    parse_header, Code, MalformedError, PPPPayload, Packet, Payload, ProtocolType,
+    MAX_CONTROL_PACKET_LEN,
+};
+
+#[cfg(feature = "rustls")]
+pub use self::rustls_backend::RustlsBackend;
+pub use self::tls::{EapTls, TlsBackend};
+
+// EAP codes, RFC 3748 section 4. They have the same values as the LCP ones.
+const REQUEST: Code = Code::ConfigureReq;
+const RESPONSE: Code = Code::ConfigureAck;
+const SUCCESS: Code = Code::ConfigureNack;
+const FAILURE: Code = Code::ConfigureRej;
+
+// Method Types handled by the peer state machine, RFC 3748 section 5.
+const IDENTITY: u8 = 1;
+const NOTIFICATION: u8 = 2;
+const NAK: u8 = 3;
+
+/// Length of the built-in Response buffer, used if `Config::eap_buf` is empty: a control
+/// packet minus the protocol field and the header.
+const BUILTIN_RESP_LEN: usize = MAX_CONTROL_PACKET_LEN - 2 - 4;
+
+/// Why an EAP method failed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EapError {
+    /// The Request is malformed. It is dropped, and authentication continues.
+    Malformed,
+    /// The authenticator didn't prove its identity, for example with an invalid certificate.
+    PeerInvalid,
+    /// The method can't complete.
+    Failed,
+}
+
+/// An EAP authentication method, RFC 3748 section 5.
+///
+/// Identity, Notification, Nak and MD5-Challenge are built in. Other methods are given
+/// in `Config::eap_methods`, and override the built-in MD5-Challenge if they have its Type.
+pub trait EapMethod {
+    /// Method Type, such as 13 for EAP-TLS.
+    fn method_type(&self) -> u8;
+
+    /// The authenticator started the method. Forget the state of any previous run.
+    fn start(&mut self);
+
+    /// Process the Type-Data of a Request with Identifier `id`, and write the Type-Data
+    /// of the Response to `resp`, which is as long as the peer's MRU allows. Returns the
+    /// length of the Response.
+    ///
+    /// Retransmitted Requests are answered with the previous Response, they are not
+    /// passed to the method.
+    fn process(&mut self, id: u8, req: &[u8], resp: &mut [u8]) -> Result<usize, EapError>;
+
+    /// Returns true once the authenticator may declare Success. Methods that authenticate
+    /// the authenticator return false until it proved its identity.
+    fn is_done(&self) -> bool;
+
+    /// Master Session Key, RFC 3748 section 7.10, once the method is done. Methods that
+    /// don't derive keys return `None`.
+    fn msk(&self) -> Option<[u8; 64]> {
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Closed,
+    /// Answering Requests, waiting for Success or Failure.
+    Authenticating,
+    Opened,
+    /// The authenticator sent a Failure, or a method failed.
+    Failed,
+    /// No Success from the authenticator in time.
+    TimedOut,
+}
+
+/// Storage for the last Response.
+enum RespBuf<'a> {
+    /// Enough for Identity, Nak and MD5-Challenge.
+    Builtin([u8; BUILTIN_RESP_LEN]),
+    Config(&'a mut [u8]),
+}
+
+impl<'a> Deref for RespBuf<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Builtin(buf) => buf,
+            Self::Config(buf) => buf,
+        }
+    }
+}
+
+impl<'a> DerefMut for RespBuf<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Builtin(buf) => buf,
+            Self::Config(buf) => buf,
+        }
+    }
+}
+
+/// Extensible Authentication Protocol, RFC 3748. We only authenticate ourselves to
+/// the peer.
+pub(crate) struct EAP<'a> {
+    state: State,
+    identity: &'a [u8],
+
+    md5: Md5Challenge<'a>,
+    methods: &'a mut [&'a mut dyn EapMethod],
+    /// Type of the method the authenticator started.
+    method: Option<u8>,
+    error: Option<EapError>,
+
+    /// Identifier, Type and Type-Data of the last Response, sent again if the
+    /// authenticator retransmits its Request.
+    resp_id: Option<u8>,
+    resp: RespBuf<'a>,
+    resp_len: usize,
+
+    timer: Timer,
+    /// How long to wait for the authenticator to finish authenticating us.
+    timeout: Duration,
+}
+
+impl<'a> EAP<'a> {
+    pub fn new(
+        config: &Config<'a>,
+        methods: &'a mut [&'a mut dyn EapMethod],
+        resp: &'a mut [u8],
+    ) -> Self {
+        // The authenticator retransmits its Request as we would retransmit an
+        // Authenticate-Request.
+        let timeout = config.restart_interval.total_millis() * config.max_configure as u64;
+        Self {
+            state: State::Closed,
+            identity: config.username,
+
+            md5: Md5Challenge::new(config.username, config.password),
+            methods,
+            method: None,
+            error: None,
+
+            resp_id: None,
+            resp: match resp {
+                [] => RespBuf::Builtin([0; BUILTIN_RESP_LEN]),
+                resp => RespBuf::Config(resp),
+            },
+            resp_len: 0,
+
+            timer: Timer::Stopped,
+            timeout: Duration::from_millis(timeout),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Type of the method the authenticator used.
+    pub fn method(&self) -> Option<u8> {
+        self.method
+    }
+
+    /// Why the method failed.
+    pub fn error(&self) -> Option<EapError> {
+        self.error
+    }
+
+    /// Master Session Key derived by the method, once the authenticator declared Success.
+    pub fn msk(&self) -> Option<[u8; 64]> {
+        if self.state != State::Opened {
+            return None;
+        }
+        // The built-in methods don't derive keys.
+        let ty = self.method?;
+        let method = self.methods.iter().find(|m| m.method_type() == ty)?;
+        method.msk()
+    }
+
+    /// Start waiting for the authenticator's Requests.
+    pub fn open(&mut self) {
+        self.state = State::Authenticating;
+        self.method = None;
+        self.error = None;
+        self.resp_id = None;
+        self.timer.start();
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closed;
+        self.timer.stop();
+    }
+
+    /// Give up if the authenticator doesn't authenticate us in time.
+    pub fn poll(&mut self, now: Instant) {
+        if self.timer.poll(now, self.timeout) && self.state == State::Authenticating {
+            info!("EAP: authenticator didn't authenticate us, giving up");
+            self.state = State::TimedOut;
+        }
+    }
+
+    /// When we give up waiting for the authenticator's next request.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer.poll_at()
+    }
+
+    /// Handle a received packet, including the protocol field. Responses are at most
+    /// `max_len` long without the protocol field, such as the peer's MRU.
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        max_len: usize,
+        tx: impl FnMut(Packet<'_>),
+    ) -> Result<(), MalformedError> {
+        let (code, id, data) = parse_header(pkt).ok_or(MalformedError::TooShort)?;
+
+        info!("EAP: rx {:?}", code);
+        let old_state = self.state;
+        match (code, self.state) {
+            (_, State::Closed) => {}
+            // Requests while Opened re-authenticate the link, which stays up unless the
+            // authenticator sends a Failure.
+            (REQUEST, _) => {
+                if data.is_empty() {
+                    return Err(MalformedError::TooShort);
+                }
+                // Room for the Type and at least one byte of Type-Data, for a Nak.
+                let max_len = max_len.saturating_sub(4).min(self.resp.len());
+                if max_len < 2 {
+                    info!("EAP: no room for a Response, dropping Request");
+                    return Ok(());
+                }
+                self.request(id, data[0], &data[1..], max_len, tx);
+            }
+            (SUCCESS, State::Authenticating) => {
+                if self.success_allowed() {
+                    self.state = State::Opened;
+                    self.timer.stop();
+                } else {
+                    info!("EAP: ignoring Success before the method completed");
+                }
+            }
+            (FAILURE, State::Authenticating) | (FAILURE, State::Opened) => {
+                info!("EAP: authenticator refused our credentials");
+                self.state = State::Failed;
+                self.timer.stop();
+            }
+            // Unknown codes are silently discarded, RFC 3748 section 4.
+            _ => {}
+        }
+
+        if old_state != self.state {
+            info!("EAP: state {:?} -> {:?}", old_state, self.state);
+        }
+        Ok(())
+    }
+
+    /// Answer a Request with a Response of up to `max_len` bytes of Type and Type-Data.
+    fn request(
+        &mut self,
+        id: u8,
+        ty: u8,
+        data: &[u8],
+        max_len: usize,
+        mut tx: impl FnMut(Packet<'_>),
+    ) {
+        if self.resp_id == Some(id) {
+            trace!("EAP: retransmitted Request");
+            tx(self.send_response());
+            return;
+        }
+
+        let len = match ty {
+            IDENTITY => {
+                // A new authentication starts, any method runs again from the start.
+                self.method = None;
+                let len = self.identity.len().min(max_len - 1);
+                self.resp[1..][..len].copy_from_slice(&self.identity[..len]);
+                len
+            }
+            NOTIFICATION => {
+                match core::str::from_utf8(data) {
+                    Ok(msg) => info!("EAP: notification {:?}", msg),
+                    Err(_) => info!("EAP: notification {:?}", data),
+                }
+                0
+            }
+            // Nak is only valid in Responses.
+            NAK => return,
+            _ => match find_method(self.methods, &mut self.md5, ty) {
+                Some(method) => {
+                    if self.method != Some(ty) {
+                        info!("EAP: starting method {:?}", ty);
+                        method.start();
+                        self.method = Some(ty);
+                    }
+                    match method.process(id, data, &mut self.resp[1..max_len]) {
+                        Ok(len) => len,
+                        Err(EapError::Malformed) => {
+                            info!("EAP: dropping malformed Request");
+                            return;
+                        }
+                        Err(e) => {
+                            info!("EAP: method failed: {:?}", e);
+                            self.error = Some(e);
+                            self.state = State::Failed;
+                            self.timer.stop();
+                            return;
+                        }
+                    }
+                }
+                None => {
+                    info!("EAP: method {:?} not supported", ty);
+                    self.resp[0] = NAK;
+                    let mut len = 0;
+                    for method in self.methods.iter().take(max_len - 2) {
+                        self.resp[1 + len] = method.method_type();
+                        len += 1;
+                    }
+                    if !self
+                        .methods
+                        .iter()
+                        .any(|m| m.method_type() == MD5_CHALLENGE)
+                    {
+                        self.resp[1 + len] = MD5_CHALLENGE;
+                        len += 1;
+                    }
+                    self.resp_id = Some(id);
+                    self.resp_len = 1 + len;
+                    tx(self.send_response());
+                    return;
+                }
+            },
+        };
+
+        self.resp[0] = ty;
+        self.resp_id = Some(id);
+        self.resp_len = 1 + len;
+        tx(self.send_response());
+    }
+
+    /// Success is only accepted once the method in progress, if any, is done with it.
+    fn success_allowed(&mut self) -> bool {
+        let ty = match self.method {
+            Some(ty) => ty,
+            None => return true,
+        };
+        match find_method(self.methods, &mut self.md5, ty) {
+            Some(method) => method.is_done(),
+            None => true,
+        }
+    }
+
+    fn send_response(&mut self) -> Packet<'_> {
+        Packet {
+            proto: ProtocolType::EAP,
+            payload: Payload::PPP(
+                RESPONSE,
+                self.resp_id.unwrap_or(0),
+                PPPPayload::Raw(&mut self.resp[..self.resp_len]),
+            ),
+        }
+    }
+}
+
+/// Method with Type `ty`. The configured methods take precedence over the built-in ones.
+fn find_method<'m, 'a: 'm>(
+    methods: &'m mut [&'a mut dyn EapMethod],
+    md5: &'m mut Md5Challenge<'a>,
+    ty: u8,
+) -> Option<&'m mut dyn EapMethod> {
+    if let Some(method) = methods.iter_mut().find(|m| m.method_type() == ty) {
+        return Some(&mut **method);
+    }
+    match ty {
+        MD5_CHALLENGE => Some(md5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::consts::*;
+    use heapless::Vec;
+
+    use super::*;
+    use crate::ppp::chap::md5_response;
+
+    type Pkt = Vec<u8, U64>;
+
+    const TEST_METHOD: u8 = 254;
+
+    /// Method that is done once it gets a Request with "done". Its Responses hold the
+    /// number of Requests it processed.
+    struct TestMethod {
+        processed: u8,
+        done: bool,
+    }
+
+    impl EapMethod for TestMethod {
+        fn method_type(&self) -> u8 {
+            TEST_METHOD
+        }
+
+        fn start(&mut self) {
+            self.processed = 0;
+            self.done = false;
+        }
+
+        fn process(&mut self, _id: u8, req: &[u8], resp: &mut [u8]) -> Result<usize, EapError> {
+            self.processed += 1;
+            self.done = req == b"done";
+            resp[0] = self.processed;
+            Ok(1)
+        }
+
+        fn is_done(&self) -> bool {
+            self.done
+        }
+    }
+
+    fn config<'a>() -> Config<'a> {
+        Config {
+            username: b"user",
+            password: b"pass",
+            ..Config::default()
+        }
+    }
+
+    /// EAP packet of `code`, with `data` after the header.
+    fn packet(code: Code, id: u8, data: &[u8]) -> Pkt {
+        let mut pkt = Pkt::new();
+        unwrap!(pkt.extend_from_slice(&[0xc2, 0x27, code.into(), id]));
+        unwrap!(pkt.extend_from_slice(&(4 + data.len() as u16).to_be_bytes()));
+        unwrap!(pkt.extend_from_slice(data));
+        pkt
+    }
+
+    /// Handle a packet from the authenticator, returning our Response if any.
+    fn receive(eap: &mut EAP<'_>, code: Code, id: u8, data: &[u8]) -> Option<Pkt> {
+        let mut pkt = packet(code, id, data);
+        let mut resp = None;
+        unwrap!(eap.handle(&mut pkt, 1500, |p| {
+            let mut buf = Pkt::new();
+            unwrap!(buf.resize_default(p.buffer_len()));
+            p.emit(&mut buf);
+            resp = Some(buf);
+        }));
+        resp
+    }
+
+    /// Handle a Request of Type `ty`, returning the Type and Type-Data of our Response.
+    fn request(eap: &mut EAP<'_>, id: u8, ty: u8, data: &[u8]) -> Pkt {
+        let mut req = unwrap!(Pkt::from_slice(&[ty]));
+        unwrap!(req.extend_from_slice(data));
+        let resp = unwrap!(receive(eap, REQUEST, id, &req));
+        assert_eq!(resp[..4], [0xc2, 0x27, RESPONSE.into(), id]);
+        assert_eq!(
+            u16::from_be_bytes([resp[4], resp[5]]) as usize,
+            resp.len() - 2
+        );
+        unwrap!(Pkt::from_slice(&resp[6..]))
+    }
+
+    #[test]
+    fn identity() {
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+        eap.open();
+        assert_eq!(request(&mut eap, 1, IDENTITY, b"")[..], *b"\x01user");
+        assert_eq!(eap.state(), State::Authenticating);
+    }
+
+    #[test]
+    fn response_len() {
+        // Truncated to the configured buffer.
+        let mut buf = [0; 3];
+        let mut eap = EAP::new(&config(), &mut [], &mut buf);
+        eap.open();
+        assert_eq!(request(&mut eap, 1, IDENTITY, b"")[..], *b"\x01us");
+
+        // Truncated to what can be sent.
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+        eap.open();
+        let mut pkt = packet(REQUEST, 1, &[IDENTITY]);
+        let mut resp = None;
+        unwrap!(eap.handle(&mut pkt, 4 + 2, |p| {
+            let mut buf = Pkt::new();
+            unwrap!(buf.resize_default(p.buffer_len()));
+            p.emit(&mut buf);
+            resp = Some(buf);
+        }));
+        assert_eq!(unwrap!(resp)[6..], *b"\x01u");
+
+        // Dropped if not even a Nak fits.
+        let mut pkt = packet(REQUEST, 2, &[IDENTITY]);
+        unwrap!(eap.handle(&mut pkt, 4 + 1, |_| unreachable!()));
+    }
+
+    #[test]
+    fn nak() {
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+        eap.open();
+        assert_eq!(request(&mut eap, 1, 99, b"")[..], [NAK, MD5_CHALLENGE]);
+
+        let mut method = TestMethod {
+            processed: 0,
+            done: false,
+        };
+        let mut methods: [&mut dyn EapMethod; 1] = [&mut method];
+        let mut eap = EAP::new(&config(), &mut methods, &mut []);
+        eap.open();
+        let nak = [NAK, TEST_METHOD, MD5_CHALLENGE];
+        assert_eq!(request(&mut eap, 1, 99, b"")[..], nak);
+        // Nak is only valid in Responses.
+        assert_eq!(receive(&mut eap, REQUEST, 2, &[NAK, 4]), None);
+        assert_eq!(eap.method(), None);
+    }
+
+    #[test]
+    fn md5_challenge() {
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+        eap.open();
+        let challenge = [0x5a; 16];
+        let mut req = [16; 17];
+        req[1..].copy_from_slice(&challenge);
+        let resp = request(&mut eap, 7, MD5_CHALLENGE, &req);
+
+        // Same as CHAP with MD5.
+        assert_eq!(resp[..2], [MD5_CHALLENGE, 16]);
+        assert_eq!(resp[2..18], md5_response(7, b"pass", &challenge));
+        assert_eq!(resp[18..], *b"user");
+        assert_eq!(eap.method(), Some(MD5_CHALLENGE));
+
+        // Retransmitted Requests get the same Response.
+        assert_eq!(request(&mut eap, 7, MD5_CHALLENGE, &req), resp);
+        // A truncated challenge is dropped.
+        assert_eq!(receive(&mut eap, REQUEST, 8, &[MD5_CHALLENGE, 16, 1]), None);
+
+        assert_eq!(receive(&mut eap, SUCCESS, 8, &[]), None);
+        assert_eq!(eap.state(), State::Opened);
+    }
+
+    #[test]
+    fn success_allowed() {
+        let mut method = TestMethod {
+            processed: 0,
+            done: false,
+        };
+        let mut methods: [&mut dyn EapMethod; 1] = [&mut method];
+        let mut eap = EAP::new(&config(), &mut methods, &mut []);
+        eap.open();
+
+        assert_eq!(request(&mut eap, 1, TEST_METHOD, b"")[..], [TEST_METHOD, 1]);
+        // The Response to a retransmitted Request is not processed again.
+        assert_eq!(request(&mut eap, 1, TEST_METHOD, b"")[..], [TEST_METHOD, 1]);
+
+        // The method is not done, Success is ignored.
+        receive(&mut eap, SUCCESS, 2, &[]);
+        assert_eq!(eap.state(), State::Authenticating);
+
+        let resp = request(&mut eap, 2, TEST_METHOD, b"done");
+        assert_eq!(resp[..], [TEST_METHOD, 2]);
+        receive(&mut eap, SUCCESS, 3, &[]);
+        assert_eq!(eap.state(), State::Opened);
+
+        // Without a method, Success is always accepted.
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+        eap.open();
+        request(&mut eap, 1, IDENTITY, b"");
+        receive(&mut eap, SUCCESS, 2, &[]);
+        assert_eq!(eap.state(), State::Opened);
+    }
+
+    #[test]
+    fn failure() {
+        let mut eap = EAP::new(&config(), &mut [], &mut []);
+
+        // Ignored while closed.
+        receive(&mut eap, FAILURE, 1, &[]);
+        assert_eq!(eap.state(), State::Closed);
+
+        eap.open();
+        receive(&mut eap, FAILURE, 1, &[]);
+        assert_eq!(eap.state(), State::Failed);
+        // Nothing more happens once failed.
+        receive(&mut eap, SUCCESS, 2, &[]);
+        assert_eq!(eap.state(), State::Failed);
+
+        // The link is taken down if the authenticator changes its mind.
+        eap.open();
+        receive(&mut eap, SUCCESS, 1, &[]);
+        assert_eq!(eap.state(), State::Opened);
+        receive(&mut eap, FAILURE, 2, &[]);
+        assert_eq!(eap.state(), State::Failed);
+    }
+}
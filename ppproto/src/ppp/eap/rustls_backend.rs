@@ -0,0 +1,331 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use ::rustls::pki_types::ServerName;
+use ::rustls::{ClientConfig, ClientConnection, Error, ProtocolVersion};
+
+use super::tls::TlsBackend;
+use super::EapError;
+use crate::fmt::*;
+
+/// EAP-TLS backend based on rustls.
+pub struct RustlsBackend {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    conn: Option<ClientConnection>,
+}
+
+impl RustlsBackend {
+    /// `config` holds our certificate and the roots the authenticator's certificate is
+    /// verified against, for `server_name`.
+    pub fn new(config: Arc<ClientConfig>, server_name: ServerName<'static>) -> Self {
+        Self {
+            config,
+            server_name,
+            conn: None,
+        }
+    }
+
+    /// The TLS connection, once a handshake started.
+    pub fn connection(&self) -> Option<&ClientConnection> {
+        self.conn.as_ref()
+    }
+}
+
+impl TlsBackend for RustlsBackend {
+    fn start(&mut self) -> Result<(), EapError> {
+        let conn = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|_| EapError::Failed)?;
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    fn receive(&mut self, mut data: &[u8]) -> Result<(), EapError> {
+        let conn = self.conn.as_mut().ok_or(EapError::Failed)?;
+        while !data.is_empty() {
+            conn.read_tls(&mut data).map_err(|_| EapError::Failed)?;
+            let state = conn.process_new_packets().map_err(|e| {
+                let e = match e {
+                    Error::InvalidCertificate(_) | Error::NoCertificatesPresented => {
+                        EapError::PeerInvalid
+                    }
+                    _ => EapError::Failed,
+                };
+                info!("EAP-TLS: handshake failed: {:?}", e);
+                e
+            })?;
+
+            // With TLS 1.3 the authenticator sends one byte of application data once
+            // the handshake is complete, RFC 9190 section 2.5. Drop it.
+            let mut plaintext = vec![0; state.plaintext_bytes_to_read()];
+            conn.reader()
+                .read_exact(&mut plaintext)
+                .map_err(|_| EapError::Failed)?;
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, mut buf: &mut [u8]) -> usize {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return 0,
+        };
+        let len = buf.len();
+        while conn.wants_write() && !buf.is_empty() {
+            if conn.write_tls(&mut buf).is_err() {
+                break;
+            }
+        }
+        len - buf.len()
+    }
+
+    fn is_established(&self) -> bool {
+        matches!(&self.conn, Some(conn) if !conn.is_handshaking())
+    }
+
+    fn export_key_material(&self, out: &mut [u8; 128]) -> Result<(), EapError> {
+        let conn = match &self.conn {
+            Some(conn) if !conn.is_handshaking() => conn,
+            _ => return Err(EapError::Failed),
+        };
+        let (label, context): (&[u8], _) = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => (b"EXPORTER_EAP_TLS_Key_Material", Some(&[0x0d][..])),
+            _ => (b"client EAP encryption", None),
+        };
+        conn.export_keying_material(out, label, context)
+            .map_err(|_| EapError::Failed)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::io::Write;
+
+    use ::rustls::crypto::ring::default_provider;
+    use ::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use ::rustls::server::WebPkiClientVerifier;
+    use ::rustls::version::{TLS12, TLS13};
+    use ::rustls::{RootCertStore, ServerConfig, ServerConnection, SupportedProtocolVersion};
+
+    use super::*;
+    use crate::ppp::eap::{EapMethod, EapTls, State, EAP};
+    use crate::ppp::Config;
+    use crate::wire::MAX_CONTROL_PACKET_LEN;
+
+    // Generated by testdata/gen.sh.
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    const OTHER_CA: &[u8] = include_bytes!("testdata/other-ca.der");
+    const SERVER: &[u8] = include_bytes!("testdata/server.der");
+    const SERVER_KEY: &[u8] = include_bytes!("testdata/server.key.der");
+    const CLIENT: &[u8] = include_bytes!("testdata/client.der");
+    const CLIENT_KEY: &[u8] = include_bytes!("testdata/client.key.der");
+
+    fn roots(ca: &'static [u8]) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(ca)).unwrap();
+        roots
+    }
+
+    fn key(der: &'static [u8]) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::try_from(der).unwrap()
+    }
+
+    /// EAP-TLS authenticator requiring a client certificate, sending its TLS messages in
+    /// fragments of `frag_len` bytes.
+    struct Authenticator {
+        conn: ServerConnection,
+        frag_len: usize,
+        id: u8,
+        /// Longest Response of the peer, including the protocol field.
+        max_resp_len: usize,
+    }
+
+    impl Authenticator {
+        fn new(version: &'static SupportedProtocolVersion) -> Self {
+            let provider = Arc::new(default_provider());
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots(CA)), provider.clone())
+                    .build()
+                    .unwrap();
+            let mut config = ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[version])
+                .unwrap()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![CertificateDer::from(SERVER)], key(SERVER_KEY))
+                .unwrap();
+            config.send_tls13_tickets = 0;
+            Self {
+                conn: ServerConnection::new(Arc::new(config)).unwrap(),
+                frag_len: 200,
+                id: 0,
+                max_resp_len: 0,
+            }
+        }
+
+        /// Send an EAP-TLS Request with `data` after the Type, and return the Response
+        /// after the Type, if any.
+        fn request(&mut self, eap: &mut EAP<'_>, mru: u16, data: &[u8]) -> Option<Vec<u8>> {
+            self.id = self.id.wrapping_add(1);
+            let mut pkt = vec![0xc2, 0x27, 1, self.id, 0, 0, 13];
+            pkt.extend_from_slice(data);
+            let len = pkt.len() as u16 - 2;
+            pkt[4..6].copy_from_slice(&len.to_be_bytes());
+
+            let mut resp = None;
+            eap.handle(&mut pkt, mru as usize, |p| {
+                let mut buf = vec![0; p.buffer_len()];
+                p.emit(&mut buf);
+                resp = Some(buf);
+            })
+            .unwrap();
+            let resp = resp?;
+            assert_eq!(resp[..4], [0xc2, 0x27, 2, self.id]);
+            assert_eq!(resp[6], 13);
+            self.max_resp_len = self.max_resp_len.max(resp.len());
+            Some(resp[7..].to_vec())
+        }
+
+        /// Run the handshake. Returns false if the peer stopped answering.
+        fn handshake(&mut self, eap: &mut EAP<'_>, mru: u16) -> bool {
+            let mut req = vec![0x20];
+            let mut msg = Vec::new();
+            loop {
+                let resp = match self.request(eap, mru, &req) {
+                    Some(resp) => resp,
+                    None => return false,
+                };
+
+                // Reassemble the peer's message, acknowledging its fragments.
+                let flags = resp[0];
+                let header = if flags & 0x80 != 0 { 5 } else { 1 };
+                msg.extend_from_slice(&resp[header..]);
+                if flags & 0x40 != 0 {
+                    req = vec![0];
+                    continue;
+                }
+                let mut rd = &msg[..];
+                while !rd.is_empty() {
+                    self.conn.read_tls(&mut rd).unwrap();
+                }
+                self.conn.process_new_packets().unwrap();
+                msg.clear();
+
+                // With TLS 1.3 the handshake ends with one byte of application data,
+                // RFC 9190 section 2.5.
+                if !self.conn.is_handshaking()
+                    && self.conn.protocol_version() == Some(ProtocolVersion::TLSv1_3)
+                    && resp.len() > 1
+                {
+                    self.conn.writer().write_all(&[0]).unwrap();
+                }
+                let mut out = Vec::new();
+                while self.conn.wants_write() {
+                    self.conn.write_tls(&mut out).unwrap();
+                }
+                // The peer acknowledged our last message.
+                if out.is_empty() {
+                    return true;
+                }
+
+                let frags: Vec<_> = out.chunks(self.frag_len).collect();
+                for (i, frag) in frags.iter().enumerate() {
+                    req = vec![0];
+                    if i == 0 && frags.len() > 1 {
+                        req[0] |= 0x80;
+                        req.extend_from_slice(&(out.len() as u32).to_be_bytes());
+                    }
+                    if i + 1 < frags.len() {
+                        req[0] |= 0x40;
+                        req.extend_from_slice(frag);
+                        assert_eq!(self.request(eap, mru, &req).unwrap(), [0]);
+                    } else {
+                        req.extend_from_slice(frag);
+                    }
+                }
+            }
+        }
+
+        /// The MSK, the first half of the Key_Material.
+        fn msk(&self) -> [u8; 64] {
+            let (label, context): (&[u8], _) = match self.conn.protocol_version() {
+                Some(ProtocolVersion::TLSv1_3) => {
+                    (b"EXPORTER_EAP_TLS_Key_Material", Some(&[0x0d][..]))
+                }
+                _ => (b"client EAP encryption", None),
+            };
+            let mut key_material = [0; 128];
+            self.conn
+                .export_keying_material(&mut key_material, label, context)
+                .unwrap();
+            let mut msk = [0; 64];
+            msk.copy_from_slice(&key_material[..64]);
+            msk
+        }
+    }
+
+    fn backend(ca: &'static [u8], version: &'static SupportedProtocolVersion) -> RustlsBackend {
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots(ca))
+            .with_client_auth_cert(vec![CertificateDer::from(CLIENT)], key(CLIENT_KEY))
+            .unwrap();
+        RustlsBackend::new(
+            Arc::new(config),
+            ServerName::try_from("auth.example").unwrap(),
+        )
+    }
+
+    #[test]
+    fn handshake_and_key_export() {
+        for &version in [&TLS13, &TLS12].iter() {
+            for &mru in [1500, 300, 128].iter() {
+                let mut tx_buf = [0; 4096];
+                let mut tls = EapTls::new(backend(CA, version), &mut tx_buf);
+                let mut methods: [&mut dyn EapMethod; 1] = [&mut tls];
+                let config = Config {
+                    username: b"unit",
+                    ..Default::default()
+                };
+                let mut resp = [0; 1496];
+                let mut eap = EAP::new(&config, &mut methods, &mut resp);
+                eap.open();
+
+                let mut auth = Authenticator::new(version);
+                assert!(auth.handshake(&mut eap, mru));
+                assert!(auth.conn.peer_certificates().is_some());
+                // Fragments fill the MRU, but never exceed it.
+                assert!(auth.max_resp_len <= 2 + mru as usize);
+                if mru == 1500 {
+                    assert!(auth.max_resp_len > MAX_CONTROL_PACKET_LEN);
+                }
+                assert_eq!(eap.msk(), None);
+
+                let mut success = [0xc2, 0x27, 3, auth.id, 0, 4];
+                eap.handle(&mut success, mru as usize, |_| {}).unwrap();
+                assert_eq!(eap.state(), State::Opened);
+                assert_eq!(eap.msk(), Some(auth.msk()));
+            }
+        }
+    }
+
+    #[test]
+    fn authenticator_not_trusted() {
+        let mut tx_buf = [0; 4096];
+        let mut tls = EapTls::new(backend(OTHER_CA, &TLS13), &mut tx_buf);
+        let mut methods: [&mut dyn EapMethod; 1] = [&mut tls];
+        let config = Config {
+            username: b"unit",
+            ..Default::default()
+        };
+        let mut eap = EAP::new(&config, &mut methods, &mut []);
+        eap.open();
+
+        let mut auth = Authenticator::new(&TLS13);
+        assert!(!auth.handshake(&mut eap, 1500));
+        assert_eq!(eap.state(), State::Failed);
+        assert_eq!(eap.error(), Some(EapError::PeerInvalid));
+    }
+}
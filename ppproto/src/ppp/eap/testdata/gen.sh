@@ -0,0 +1,29 @@
+#!/bin/sh
+# Certificates for the EAP-TLS tests. The authenticator's certificate is signed by `ca`,
+# `other-ca` signs nothing and makes the peer reject it.
+set -e
+cd "$(dirname "$0")"
+dates="-not_before 20200101000000Z -not_after 99991231235959Z"
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -outform DER -out "$1.key.der"
+}
+
+for ca in ca other-ca; do
+    key $ca
+    openssl req -new -x509 -key $ca.key.der -subj "/CN=$ca" $dates \
+        -addext basicConstraints=critical,CA:TRUE -outform DER -out $ca.der
+done
+
+leaf() {
+    key $1
+    openssl req -new -key $1.key.der -subj "/CN=$2" -out $1.csr
+    openssl x509 -req -in $1.csr -CA ca.der -CAkey ca.key.der $dates -extfile /dev/stdin \
+        -outform DER -out $1.der
+    rm $1.csr
+}
+
+printf 'subjectAltName=DNS:auth.example\nextendedKeyUsage=serverAuth\nbasicConstraints=CA:FALSE\n' |
+    leaf server auth.example
+printf 'extendedKeyUsage=clientAuth\nbasicConstraints=CA:FALSE\n' | leaf client unit
+rm ca.key.der other-ca.key.der
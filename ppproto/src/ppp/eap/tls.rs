@@ -0,0 +1,221 @@
+use crate::fmt::*;
+
+use super::{EapError, EapMethod};
+
+/// EAP-TLS method Type.
+const EAP_TLS: u8 = 13;
+
+// Flags, RFC 5216 section 3.1.
+const LENGTH_INCLUDED: u8 = 0x80;
+const MORE_FRAGMENTS: u8 = 0x40;
+const START: u8 = 0x20;
+
+/// TLS client used by EAP-TLS, such as `RustlsBackend` in std builds.
+///
+/// EAP-TLS only carries the TLS records. The backend does the handshake: it presents
+/// our certificate, and must verify the authenticator's.
+pub trait TlsBackend {
+    /// Start a new handshake, discarding any previous session.
+    fn start(&mut self) -> Result<(), EapError>;
+
+    /// Process TLS records received from the authenticator. They may end in the middle
+    /// of a record, the rest follows in the next call.
+    ///
+    /// Returns `EapError::PeerInvalid` if the authenticator's certificate is not valid.
+    fn receive(&mut self, data: &[u8]) -> Result<(), EapError>;
+
+    /// Write TLS records to send to the authenticator to `buf`. Returns how many bytes
+    /// were written, 0 if there's nothing to send.
+    fn transmit(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Returns true once the handshake is complete.
+    fn is_established(&self) -> bool;
+
+    /// Export the 128 bytes of Key_Material once the handshake is complete. The TLS
+    /// exporter label depends on the version: "client EAP encryption" for TLS 1.2,
+    /// RFC 5216 section 2.3, and "EXPORTER_EAP_TLS_Key_Material" with context 0x0D for
+    /// TLS 1.3, RFC 9190 section 2.3.
+    fn export_key_material(&self, out: &mut [u8; 128]) -> Result<(), EapError>;
+}
+
+/// EAP-TLS, RFC 5216. Authenticates us with a client certificate.
+///
+/// Received TLS messages are passed to the backend fragment by fragment, so they need
+/// no buffer. The messages we send are buffered in `tx_buf` to be fragmented, it must
+/// hold the longest of them, usually the one with our certificate chain. Fragments are at
+/// most as long as `Config::eap_buf` allows.
+pub struct EapTls<'a, T: TlsBackend> {
+    backend: T,
+
+    /// TLS message being sent, and how much of it was sent already.
+    tx_buf: &'a mut [u8],
+    tx_len: usize,
+    tx_pos: usize,
+
+    /// Length of the TLS message being received, if announced, and how much of it
+    /// was received already.
+    rx_total: Option<u32>,
+    rx_len: u32,
+}
+
+impl<'a, T: TlsBackend> EapTls<'a, T> {
+    pub fn new(backend: T, tx_buf: &'a mut [u8]) -> Self {
+        Self {
+            backend,
+            tx_buf,
+            tx_len: 0,
+            tx_pos: 0,
+            rx_total: None,
+            rx_len: 0,
+        }
+    }
+
+    pub fn backend(&self) -> &T {
+        &self.backend
+    }
+
+    /// The backend, for example to inspect the session once the handshake is complete.
+    pub fn backend_mut(&mut self) -> &mut T {
+        &mut self.backend
+    }
+
+    /// Receive a fragment of the authenticator's TLS message. Returns the length of the
+    /// Response.
+    fn receive(&mut self, flags: u8, data: &[u8], resp: &mut [u8]) -> Result<usize, EapError> {
+        // We must not get TLS data before all our fragments are acknowledged.
+        if self.tx_pos < self.tx_len {
+            return Err(EapError::Malformed);
+        }
+
+        let rx_len = self.rx_len.saturating_add(data.len() as u32);
+        if matches!(self.rx_total, Some(total) if rx_len > total) {
+            info!("EAP-TLS: message longer than announced");
+            return Err(EapError::Malformed);
+        }
+        self.backend.receive(data)?;
+        self.rx_len = rx_len;
+
+        // Acknowledge the fragment, the rest of the message follows.
+        if flags & MORE_FRAGMENTS != 0 {
+            resp[0] = 0;
+            return Ok(1);
+        }
+        self.rx_total = None;
+        self.rx_len = 0;
+
+        // The message is complete, reply with the backend's message, or acknowledge
+        // it if there is none, such as after the authenticator's Finished.
+        self.fill_tx()?;
+        if self.tx_len == 0 {
+            resp[0] = 0;
+            return Ok(1);
+        }
+        Ok(self.send_fragment(resp))
+    }
+
+    /// Take the next message of the backend.
+    fn fill_tx(&mut self) -> Result<(), EapError> {
+        self.tx_len = 0;
+        self.tx_pos = 0;
+        loop {
+            let n = self.backend.transmit(&mut self.tx_buf[self.tx_len..]);
+            if n == 0 {
+                break;
+            }
+            self.tx_len += n;
+            if self.tx_len == self.tx_buf.len() {
+                info!("EAP-TLS: message doesn't fit in tx_buf");
+                return Err(EapError::Failed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the next fragment of the message being sent to `resp`. The first fragment
+    /// of a fragmented message carries its total length.
+    fn send_fragment(&mut self, resp: &mut [u8]) -> usize {
+        let mut flags = 0;
+        let mut header = 1;
+        if self.tx_pos == 0 && self.tx_len > resp.len() - 1 {
+            flags |= LENGTH_INCLUDED;
+            resp[1..5].copy_from_slice(&(self.tx_len as u32).to_be_bytes());
+            header = 5;
+        }
+
+        let n = (self.tx_len - self.tx_pos).min(resp.len() - header);
+        resp[header..][..n].copy_from_slice(&self.tx_buf[self.tx_pos..][..n]);
+        self.tx_pos += n;
+        if self.tx_pos < self.tx_len {
+            flags |= MORE_FRAGMENTS;
+        }
+        resp[0] = flags;
+        trace!(
+            "EAP-TLS: tx fragment {:?}/{:?} bytes",
+            self.tx_pos,
+            self.tx_len
+        );
+        header + n
+    }
+}
+
+impl<'a, T: TlsBackend> EapMethod for EapTls<'a, T> {
+    fn method_type(&self) -> u8 {
+        EAP_TLS
+    }
+
+    fn start(&mut self) {
+        self.tx_len = 0;
+        self.tx_pos = 0;
+        self.rx_total = None;
+        self.rx_len = 0;
+    }
+
+    fn process(&mut self, _id: u8, req: &[u8], resp: &mut [u8]) -> Result<usize, EapError> {
+        if req.is_empty() {
+            return Err(EapError::Malformed);
+        }
+        let flags = req[0];
+        let mut data = &req[1..];
+        if flags & LENGTH_INCLUDED != 0 {
+            if data.len() < 4 {
+                return Err(EapError::Malformed);
+            }
+            // Only the first fragment of a message is required to carry the length.
+            if self.rx_len == 0 {
+                self.rx_total = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+            }
+            data = &data[4..];
+        }
+
+        if flags & START != 0 {
+            info!("EAP-TLS: start");
+            self.start();
+            self.backend.start()?;
+            self.fill_tx()?;
+            return Ok(self.send_fragment(resp));
+        }
+
+        // An empty Request acknowledges our last fragment.
+        if self.tx_pos < self.tx_len && data.is_empty() && flags & MORE_FRAGMENTS == 0 {
+            return Ok(self.send_fragment(resp));
+        }
+
+        self.receive(flags, data, resp)
+    }
+
+    fn is_done(&self) -> bool {
+        self.backend.is_established() && self.tx_pos == self.tx_len
+    }
+
+    /// The MSK is the first half of the Key_Material, the EMSK the second.
+    fn msk(&self) -> Option<[u8; 64]> {
+        if !self.is_done() {
+            return None;
+        }
+        let mut key_material = [0; 128];
+        self.backend.export_key_material(&mut key_material).ok()?;
+        let mut msk = [0; 64];
+        msk.copy_from_slice(&key_material[..64]);
+        Some(msk)
+    }
+}
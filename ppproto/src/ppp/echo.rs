@@ -0,0 +1,241 @@
+use crate::fmt::*;
+
+use super::lcp::bad_magic;
+use super::timer::Timer;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType};
+
+/// State of an on-demand Echo-Request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Ping {
+    Idle,
+    /// Requested, the Echo-Request is sent on the next poll.
+    Queued,
+    Sent {
+        id: u8,
+        at: Instant,
+    },
+    Done(Duration),
+}
+
+/// LCP Echo-Requests: keepalive to detect a peer that stopped responding,
+/// and on-demand pings to measure the round-trip time.
+pub(crate) struct Echo {
+    id: u8,
+    timer: Timer,
+    /// Keepalive Echo-Requests sent since the last matching Echo-Reply.
+    pending: u8,
+    ping: Ping,
+
+    interval: Option<Duration>,
+    max_failure: u8,
+}
+
+impl Echo {
+    pub fn new(config: &Config<'_>) -> Self {
+        Self {
+            id: 0,
+            timer: Timer::Stopped,
+            pending: 0,
+            ping: Ping::Idle,
+
+            interval: config.echo_interval,
+            max_failure: config.echo_failure,
+        }
+    }
+
+    /// LCP is up, start sending Echo-Requests.
+    pub fn start(&mut self) {
+        self.pending = 0;
+        if self.interval.is_some() {
+            self.timer.start();
+        }
+    }
+
+    /// LCP is down, stop sending Echo-Requests. An outstanding ping is abandoned.
+    pub fn stop(&mut self) {
+        self.timer.stop();
+        if matches!(self.ping, Ping::Queued | Ping::Sent { .. }) {
+            self.ping = Ping::Idle;
+        }
+    }
+
+    /// Send an Echo-Request on the next poll, to measure the round-trip time.
+    pub fn ping(&mut self) {
+        self.ping = Ping::Queued;
+    }
+
+    /// Returns the round-trip time of the last ping, once its Echo-Reply arrived.
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        match self.ping {
+            Ping::Done(rtt) => Some(rtt),
+            _ => None,
+        }
+    }
+
+    /// Drive the echo timer. Returns true if the peer missed Max-Failure Echo-Replies.
+    pub fn poll(&mut self, now: Instant, magic: u32, mut tx: impl FnMut(Packet<'_>)) -> bool {
+        if self.ping == Ping::Queued {
+            let id = self.send_request(magic, &mut tx);
+            self.ping = Ping::Sent { id, at: now };
+        }
+
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        if !self.timer.poll(now, interval) {
+            return false;
+        }
+
+        if self.pending >= self.max_failure {
+            info!("LCP: no reply to {:?} Echo-Requests", self.pending);
+            return true;
+        }
+
+        self.pending += 1;
+        self.timer.start();
+        self.send_request(magic, tx);
+        false
+    }
+
+    /// When the next Echo-Request is due, right away if a ping is queued.
+    pub fn poll_at(&self) -> Option<Instant> {
+        if self.ping == Ping::Queued {
+            return Some(Instant::ZERO);
+        }
+        self.timer.poll_at()
+    }
+
+    /// Send an Echo-Request with a new Identifier, and return it.
+    fn send_request(&mut self, magic: u32, mut tx: impl FnMut(Packet<'_>)) -> u8 {
+        self.id = self.id.wrapping_add(1);
+        trace!("LCP: tx {:?} id {:?}", Code::EchoReq, self.id);
+        let mut data = magic.to_be_bytes();
+        tx(Packet {
+            proto: ProtocolType::LCP,
+            payload: Payload::PPP(Code::EchoReq, self.id, PPPPayload::Raw(&mut data)),
+        });
+        self.id
+    }
+
+    /// Handle a received Echo-Reply, including the protocol field.
+    ///
+    /// A reply carrying our `magic` is our own request coming back, and one not carrying
+    /// `magic_remote` isn't from the peer; neither counts as an answer.
+    pub fn received_reply(&mut self, now: Instant, pkt: &[u8], magic: u32, magic_remote: u32) {
+        if pkt.len() < 10 {
+            return;
+        }
+        let id = pkt[3];
+        let reply_magic = u32::from_be_bytes([pkt[6], pkt[7], pkt[8], pkt[9]]);
+
+        if bad_magic(reply_magic, magic, magic_remote) {
+            info!("LCP: ignoring Echo-Reply with wrong magic number");
+            return;
+        }
+
+        if let Ping::Sent { id: ping_id, at } = self.ping {
+            if id == ping_id {
+                let rtt = now - at;
+                info!("LCP: ping reply, rtt {:?} ms", rtt.total_millis());
+                self.ping = Ping::Done(rtt);
+                self.pending = 0;
+                return;
+            }
+        }
+
+        // Any of the outstanding keepalive requests may be answered.
+        if self.id.wrapping_sub(id) < self.pending {
+            self.pending = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: u32 = 0x1111_1111;
+    const MAGIC_REMOTE: u32 = 0x2222_2222;
+
+    /// Polls `echo`, returning the Identifier of the Echo-Request sent, if any. Polls twice,
+    /// as `PPP` does, to arm a just started timer.
+    fn poll(echo: &mut Echo, now: Instant) -> Option<u8> {
+        let mut sent = None;
+        for _ in 0..2 {
+            assert!(!echo.poll(now, MAGIC, |pkt| match pkt.payload {
+                Payload::PPP(Code::EchoReq, id, _) => sent = Some(id),
+                _ => unreachable!(),
+            }));
+        }
+        sent
+    }
+
+    fn reply(id: u8, magic: u32) -> [u8; 10] {
+        let m = magic.to_be_bytes();
+        [0xc0, 0x21, 10, id, 0, 8, m[0], m[1], m[2], m[3]]
+    }
+
+    fn secs(secs: u64) -> Instant {
+        Instant::ZERO + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn ping_rtt() {
+        let mut echo = Echo::new(&Config::default());
+        echo.start();
+        echo.ping();
+        let id = unwrap!(poll(&mut echo, secs(1)));
+        assert_eq!(echo.ping_rtt(), None);
+
+        // Wrong Identifier, or our own request looped back.
+        echo.received_reply(
+            secs(2),
+            &reply(id.wrapping_add(1), MAGIC_REMOTE),
+            MAGIC,
+            MAGIC_REMOTE,
+        );
+        echo.received_reply(secs(2), &reply(id, MAGIC), MAGIC, MAGIC_REMOTE);
+        assert_eq!(echo.ping_rtt(), None);
+
+        let now = secs(1) + Duration::from_millis(250);
+        echo.received_reply(now, &reply(id, MAGIC_REMOTE), MAGIC, MAGIC_REMOTE);
+        assert_eq!(echo.ping_rtt(), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn keepalive_id_window() {
+        let mut echo = Echo::new(&Config {
+            echo_interval: Some(Duration::from_secs(10)),
+            echo_failure: 3,
+            ..Config::default()
+        });
+        // The Identifiers of the outstanding requests wrap around.
+        echo.id = 254;
+        echo.start();
+        assert_eq!(poll(&mut echo, secs(0)), None);
+        assert_eq!(poll(&mut echo, secs(10)), Some(255));
+        assert_eq!(poll(&mut echo, secs(20)), Some(0));
+        assert_eq!(poll(&mut echo, secs(30)), Some(1));
+        assert_eq!(echo.pending, 3);
+
+        // Identifiers outside of the outstanding ones don't count.
+        for &id in &[254, 2] {
+            echo.received_reply(secs(35), &reply(id, MAGIC_REMOTE), MAGIC, MAGIC_REMOTE);
+            assert_eq!(echo.pending, 3);
+        }
+        // The oldest outstanding request.
+        echo.received_reply(secs(35), &reply(255, MAGIC_REMOTE), MAGIC, MAGIC_REMOTE);
+        assert_eq!(echo.pending, 0);
+
+        // Without a reply the link fails after echo_failure requests.
+        assert_eq!(poll(&mut echo, secs(40)), Some(2));
+        assert_eq!(poll(&mut echo, secs(50)), Some(3));
+        assert_eq!(poll(&mut echo, secs(60)), Some(4));
+        let mut sent = false;
+        assert!(echo.poll(secs(70), MAGIC, |_| sent = true));
+        assert!(!sent);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::fmt::*;
+use core::fmt;
+
+use super::lcp::bad_magic;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{
+    Code, MalformedError, PPPPayload, Packet, Payload, ProtocolType, MAX_CONTROL_PACKET_LEN,
+};
+
+/// Longest message kept from the peer's Identification packet.
+const MAX_PEER_MESSAGE_LEN: usize = 32;
+/// Longest message we send: a control packet minus the protocol, header and magic number.
+const MAX_MESSAGE_LEN: usize = MAX_CONTROL_PACKET_LEN - 2 - 4 - 4;
+
+/// Message received in an LCP Identification packet, truncated to 32 bytes.
+///
+/// Usually a human readable string, such as the name and version of the peer's software.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Identification {
+    buf: [u8; MAX_PEER_MESSAGE_LEN],
+    len: u8,
+}
+
+impl Identification {
+    fn new(message: &[u8]) -> Self {
+        let len = message.len().min(MAX_PEER_MESSAGE_LEN);
+        let mut buf = [0; MAX_PEER_MESSAGE_LEN];
+        buf[..len].copy_from_slice(&message[..len]);
+        Self {
+            buf,
+            len: len as u8,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for Identification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match core::str::from_utf8(self.as_bytes()) {
+            Ok(s) => write!(f, "{:?}", s),
+            Err(_) => write!(f, "{:?}", self.as_bytes()),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Identification {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{=[u8]:a}", self.as_bytes())
+    }
+}
+
+/// LCP Identification and Time-Remaining packets, RFC 1570.
+pub(crate) struct Ident<'a> {
+    id: u8,
+    message: &'a [u8],
+    peer: Option<Identification>,
+    disconnect_at: Option<Instant>,
+}
+
+impl<'a> Ident<'a> {
+    pub fn new(config: &Config<'a>) -> Self {
+        Self {
+            id: 0,
+            message: config.identification,
+            peer: None,
+            disconnect_at: None,
+        }
+    }
+
+    /// Forget what the peer sent, before opening the link again.
+    pub fn reset(&mut self) {
+        self.peer = None;
+        self.disconnect_at = None;
+    }
+
+    /// LCP is down, the announced disconnection doesn't apply anymore.
+    pub fn stop(&mut self) {
+        self.disconnect_at = None;
+    }
+
+    /// The peer's Identification message, if it sent one.
+    pub fn peer(&self) -> Option<Identification> {
+        self.peer
+    }
+
+    /// Instant at which the peer announced it will disconnect.
+    pub fn disconnect_at(&self) -> Option<Instant> {
+        self.disconnect_at
+    }
+
+    /// Send our Identification message, if any.
+    pub fn send(&mut self, magic: u32, mut tx: impl FnMut(Packet<'_>)) {
+        if self.message.is_empty() {
+            return;
+        }
+
+        let len = self.message.len().min(MAX_MESSAGE_LEN);
+        let mut data = [0; 4 + MAX_MESSAGE_LEN];
+        data[..4].copy_from_slice(&magic.to_be_bytes());
+        data[4..][..len].copy_from_slice(&self.message[..len]);
+
+        self.id = self.id.wrapping_add(1);
+        trace!("LCP: tx {:?} id {:?}", Code::Identification, self.id);
+        tx(Packet {
+            proto: ProtocolType::LCP,
+            payload: Payload::PPP(
+                Code::Identification,
+                self.id,
+                PPPPayload::Raw(&mut data[..4 + len]),
+            ),
+        });
+    }
+
+    /// Handle a received Identification or Time-Remaining packet, including the protocol field.
+    ///
+    /// Packets whose magic number is not the peer's `magic_remote`, or is our own `magic`,
+    /// are ignored.
+    pub fn received(
+        &mut self,
+        now: Instant,
+        pkt: &[u8],
+        magic: u32,
+        magic_remote: u32,
+    ) -> Result<(), MalformedError> {
+        let code = Code::from(pkt[2]);
+        let len = u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+        let min_len = match code {
+            Code::TimeRemaining => 12,
+            _ => 8,
+        };
+        if len < min_len {
+            return Err(MalformedError::TooShort);
+        }
+        let pkt = &pkt[2..][..len];
+
+        let pkt_magic = u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]);
+        if bad_magic(pkt_magic, magic, magic_remote) {
+            info!("LCP: ignoring {:?} with wrong magic number", code);
+            return Ok(());
+        }
+
+        match code {
+            Code::TimeRemaining => {
+                let secs = u32::from_be_bytes([pkt[8], pkt[9], pkt[10], pkt[11]]);
+                info!("LCP: peer disconnects in {:?} s", secs);
+                self.disconnect_at = Some(now + Duration::from_secs(secs as u64));
+            }
+            _ => {
+                let peer = Identification::new(&pkt[8..]);
+                info!("LCP: peer identification {:?}", peer);
+                self.peer = Some(peer);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::consts::*;
+    use heapless::Vec;
+
+    use super::*;
+
+    const MAGIC: u32 = 0x1111_1111;
+    const MAGIC_REMOTE: u32 = 0x2222_2222;
+
+    /// LCP packet of `code` with the magic number followed by `data`.
+    fn packet(code: Code, magic: u32, data: &[u8]) -> Vec<u8, U64> {
+        let mut pkt = Vec::new();
+        unwrap!(pkt.extend_from_slice(&[0xc0, 0x21, code.into(), 1]));
+        unwrap!(pkt.extend_from_slice(&(8 + data.len() as u16).to_be_bytes()));
+        unwrap!(pkt.extend_from_slice(&magic.to_be_bytes()));
+        unwrap!(pkt.extend_from_slice(data));
+        pkt
+    }
+
+    #[test]
+    fn peer_identification() {
+        let mut ident = Ident::new(&Config::default());
+        let pkt = packet(Code::Identification, MAGIC_REMOTE, b"peer 1.0");
+        unwrap!(ident.received(Instant::ZERO, &pkt, MAGIC, MAGIC_REMOTE));
+        assert_eq!(unwrap!(ident.peer()).as_bytes(), b"peer 1.0");
+
+        // Only the first 32 bytes are kept.
+        let message = [b'x'; 40];
+        let pkt = packet(Code::Identification, MAGIC_REMOTE, &message);
+        unwrap!(ident.received(Instant::ZERO, &pkt, MAGIC, MAGIC_REMOTE));
+        assert_eq!(unwrap!(ident.peer()).as_bytes(), &message[..32]);
+
+        ident.reset();
+        assert_eq!(ident.peer(), None);
+    }
+
+    #[test]
+    fn magic_check() {
+        let mut ident = Ident::new(&Config::default());
+        // Our own packet looped back, and one from someone else than the peer.
+        for &magic in &[MAGIC, 0x3333_3333, 0] {
+            let pkt = packet(Code::Identification, magic, b"peer");
+            unwrap!(ident.received(Instant::ZERO, &pkt, MAGIC, MAGIC_REMOTE));
+            assert_eq!(ident.peer(), None);
+        }
+
+        // Without a negotiated magic number, any but ours is accepted.
+        let pkt = packet(Code::Identification, 0, b"peer");
+        unwrap!(ident.received(Instant::ZERO, &pkt, MAGIC, 0));
+        assert_eq!(unwrap!(ident.peer()).as_bytes(), b"peer");
+    }
+
+    #[test]
+    fn disconnect_at() {
+        let mut ident = Ident::new(&Config::default());
+        let now = Instant::ZERO + Duration::from_secs(100);
+
+        // The number of seconds is missing.
+        let pkt = packet(Code::TimeRemaining, MAGIC_REMOTE, &[0, 0]);
+        let res = ident.received(now, &pkt, MAGIC, MAGIC_REMOTE);
+        assert_eq!(res, Err(MalformedError::TooShort));
+        assert_eq!(ident.disconnect_at(), None);
+
+        let pkt = packet(Code::TimeRemaining, MAGIC_REMOTE, &[0, 0, 0, 60]);
+        unwrap!(ident.received(now, &pkt, MAGIC, MAGIC_REMOTE));
+        let at = Instant::ZERO + Duration::from_secs(160);
+        assert_eq!(ident.disconnect_at(), Some(at));
+
+        // It doesn't apply anymore once LCP goes down.
+        ident.stop();
+        assert_eq!(ident.disconnect_at(), None);
+    }
+}
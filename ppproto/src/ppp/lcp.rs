@@ -2,52 +2,223 @@ use crate::fmt::*;
 use core::convert::TryInto;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
+use super::chap::{self, Algorithm};
 use super::option_fsm::{Protocol, Verdict};
+use super::{Config, Rng};
 use crate::wire::ProtocolType;
 
+/// MRU used when the option is not negotiated.
+pub const DEFAULT_MRU: u16 = 1500;
+/// Smallest peer MRU we accept. Our control packets must fit in it.
+const MIN_MRU: u16 = 128;
+/// FCS-Alternatives values, RFC 1570.
+const FCS_16: u8 = 0x02;
+const FCS_32: u8 = 0x04;
+/// CHAP Algorithm values for MD5, RFC 1994, and MS-CHAPv2, RFC 2759.
+const CHAP_MD5: u8 = 0x05;
+const CHAP_MSCHAPV2: u8 = 0x81;
+
+/// Returns true if the magic number in a received packet is not the peer's. Our own
+/// means the packet was looped back. Unnegotiated magic numbers are 0 and not checked.
+pub(crate) fn bad_magic(received: u32, magic: u32, magic_remote: u32) -> bool {
+    (magic != 0 && received == magic) || (magic_remote != 0 && received != magic_remote)
+}
+
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 enum Option {
     #[num_enum(default)]
     Unknown = 0,
+    Mru = 1,
     Asyncmap = 2,
     Auth = 3,
+    QualityProtocol = 4,
+    MagicNumber = 5,
+    ProtocolFieldCompression = 7,
+    AddressControlFieldCompression = 8,
+    FcsAlternatives = 9,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AuthType {
-    None = 0,
-    PAP = 0xc023,
+    None,
+    PAP,
+    CHAP(Algorithm),
+    EAP,
 }
 
-pub(crate) struct LCP {
+pub(crate) struct LCP<'a> {
     pub auth: AuthType,
 
+    /// Largest packet we can receive, given by the size of the rx buffer.
+    mru_max: u16,
+    /// MRU we request.
+    pub mru: u16,
+    pub mru_rej: bool,
+    /// Largest packet the peer can receive.
+    pub mru_remote: u16,
+
     pub asyncmap_remote: u32,
     pub asyncmap: u32,
     pub asyncmap_rej: bool,
+
+    rng: core::option::Option<&'a mut dyn Rng>,
+    /// Our magic number, 0 if not chosen yet.
+    pub magic: u32,
+    pub magic_rej: bool,
+    /// Peer's magic number, 0 if not negotiated.
+    pub magic_remote: u32,
+    /// Buffer for the magic number we suggest to the peer in a Configure-Nak.
+    magic_nak: [u8; 4],
+    /// We picked a new magic number while handling the peer's options.
+    magic_changed: bool,
+    pub pfc_rej: bool,
+    /// The peer can receive compressed protocol fields.
+    pub pfc_remote: bool,
+    pub acfc_rej: bool,
+    /// The peer can receive frames without address and control fields.
+    pub acfc_remote: bool,
+    /// Request the peer to send a 32-bit FCS.
+    pub fcs32: bool,
+    pub fcs_rej: bool,
+    /// The peer wants to receive a 32-bit FCS.
+    pub fcs32_remote: bool,
+
+    /// Reporting-Period we request for Link-Quality-Reports, in hundredths of a second.
+    pub lqr_period: core::option::Option<u32>,
+    pub lqr_rej: bool,
+    /// Reporting-Period requested by the peer. None if it didn't ask for reports.
+    pub lqr_remote: core::option::Option<u32>,
+
+    /// Consecutive Configure-Requests carrying our own magic number.
+    loopback_count: u8,
+    max_failure: u8,
+
+    /// Our username fits in a CHAP Response with MD5, and with MS-CHAPv2.
+    chap_md5: bool,
+    chap_mschapv2: bool,
 }
 
-impl LCP {
-    pub fn new() -> Self {
+impl<'a> LCP<'a> {
+    pub fn new(rng: core::option::Option<&'a mut dyn Rng>, config: &Config<'_>) -> Self {
         Self {
             auth: AuthType::None,
+
+            mru_max: DEFAULT_MRU,
+            mru: DEFAULT_MRU,
+            mru_rej: false,
+            mru_remote: DEFAULT_MRU,
+
             asyncmap_remote: 0xFFFFFFFF,
             asyncmap: 0x00000000,
             asyncmap_rej: false,
+
+            rng,
+            magic: 0,
+            magic_rej: false,
+            magic_remote: 0,
+            magic_nak: [0; 4],
+            magic_changed: false,
+            pfc_rej: false,
+            pfc_remote: false,
+            acfc_rej: false,
+            acfc_remote: false,
+            fcs32: config.fcs32,
+            fcs_rej: false,
+            fcs32_remote: false,
+            lqr_period: config
+                .lqr_interval
+                .map(|i| (i.total_millis() / 10).max(1).min(u32::MAX as u64) as u32),
+            lqr_rej: false,
+            lqr_remote: None,
+            loopback_count: 0,
+            max_failure: config.max_failure,
+
+            chap_md5: chap::name_fits(Algorithm::Md5, config.username),
+            chap_mschapv2: chap::name_fits(Algorithm::MsChapV2, config.username),
         }
     }
+
+    /// Set the largest packet we can receive, and request it as our MRU.
+    pub fn set_max_mru(&mut self, mru: u16) {
+        self.mru_max = mru;
+        self.mru = mru;
+        self.mru_rej = false;
+    }
+
+    /// Forget the magic numbers and the loopback detection state, before opening the link again.
+    pub fn reset_magic(&mut self) {
+        self.magic = 0;
+        self.magic_rej = false;
+        self.magic_remote = 0;
+        self.loopback_count = 0;
+    }
+
+    /// Returns true if our own magic number came back Max-Failure times in a row,
+    /// which means the link is looped back.
+    pub fn looped_back(&self) -> bool {
+        self.loopback_count >= self.max_failure
+    }
+
+    /// Random source, for the authentication protocols.
+    pub fn rng(&mut self) -> core::option::Option<&mut dyn Rng> {
+        match &mut self.rng {
+            Some(rng) => Some(*rng),
+            None => None,
+        }
+    }
+
+    /// Returns a new random magic number, or 0 if there's no random source.
+    fn random_magic(&mut self) -> u32 {
+        let rng = match &mut self.rng {
+            Some(rng) => rng,
+            None => return 0,
+        };
+        // Zero is not a valid magic number.
+        loop {
+            let magic = rng.next_u32();
+            if magic != 0 {
+                return magic;
+            }
+        }
+    }
+
+    fn loopback_suspected(&mut self) {
+        self.loopback_count = self.loopback_count.saturating_add(1);
+        info!(
+            "LCP: own magic number came back, link may be looped back ({:?})",
+            self.loopback_count
+        );
+    }
 }
 
-impl Protocol for LCP {
+impl<'a> Protocol for LCP<'a> {
     fn protocol(&self) -> ProtocolType {
         ProtocolType::LCP
     }
 
+    fn take_own_options_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.magic_changed, false)
+    }
+
+    fn magic_number(&self) -> u32 {
+        if self.magic_rej {
+            0
+        } else {
+            self.magic
+        }
+    }
+
     fn peer_options_start(&mut self) {
         self.auth = AuthType::None;
+        self.mru_remote = DEFAULT_MRU;
+        self.magic_remote = 0;
+        self.pfc_remote = false;
+        self.acfc_remote = false;
+        self.fcs32_remote = false;
+        self.lqr_remote = None;
     }
 
     fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict {
@@ -55,6 +226,18 @@ impl Protocol for LCP {
         trace!("LCP: rx option {:?} {:?} {:?}", code, opt, data);
         match opt {
             Option::Unknown => Verdict::Rej,
+            Option::Mru => {
+                if data.len() != 2 {
+                    return Verdict::Rej;
+                }
+                let mru = u16::from_be_bytes([data[0], data[1]]);
+                if mru < MIN_MRU {
+                    const NAK: [u8; 2] = MIN_MRU.to_be_bytes();
+                    return Verdict::Nack(&NAK);
+                }
+                self.mru_remote = mru;
+                Verdict::Ack
+            }
             Option::Asyncmap => {
                 if data.len() == 4 {
                     self.asyncmap_remote = u32::from_be_bytes(data.try_into().unwrap());
@@ -63,27 +246,142 @@ impl Protocol for LCP {
                     Verdict::Rej
                 }
             }
-            Option::Auth => {
-                if data == &[0xc0, 0x23] {
+            Option::Auth => match data {
+                [0xc0, 0x23] => {
                     self.auth = AuthType::PAP;
                     Verdict::Ack
+                }
+                [0xc2, 0x23, CHAP_MD5] if self.chap_md5 => {
+                    self.auth = AuthType::CHAP(Algorithm::Md5);
+                    Verdict::Ack
+                }
+                // The Peer-Challenge of MS-CHAPv2 must be random.
+                [0xc2, 0x23, CHAP_MSCHAPV2] if self.rng.is_some() && self.chap_mschapv2 => {
+                    self.auth = AuthType::CHAP(Algorithm::MsChapV2);
+                    Verdict::Ack
+                }
+                [0xc2, 0x27] => {
+                    self.auth = AuthType::EAP;
+                    Verdict::Ack
+                }
+                // Other CHAP algorithms, suggest MD5 if our username fits in its Response.
+                [0xc2, 0x23, ..] if self.chap_md5 => Verdict::Nack(&[0xc2, 0x23, CHAP_MD5]),
+                _ => Verdict::Nack(&[0xc0, 0x23]),
+            },
+            Option::QualityProtocol => {
+                // Only Link-Quality-Report is supported.
+                if data.len() != 6 || data[..2] != [0xc0, 0x25] {
+                    return Verdict::Rej;
+                }
+                self.lqr_remote = Some(u32::from_be_bytes(data[2..].try_into().unwrap()));
+                Verdict::Ack
+            }
+            Option::MagicNumber => {
+                if data.len() != 4 {
+                    return Verdict::Rej;
+                }
+                let magic = u32::from_be_bytes(data.try_into().unwrap());
+                if magic != 0 && magic != self.magic {
+                    self.magic_remote = magic;
+                    self.loopback_count = 0;
+                    return Verdict::Ack;
+                }
+
+                // Zero is not valid, and our own magic number means the link may be
+                // looped back. Suggest another one and pick a new one for ourselves.
+                if magic != 0 {
+                    self.loopback_suspected();
+                    self.magic = self.random_magic();
+                    self.magic_changed = true;
+                }
+                match self.random_magic() {
+                    0 => Verdict::Rej,
+                    nak => {
+                        self.magic_nak = nak.to_be_bytes();
+                        Verdict::Nack(&self.magic_nak)
+                    }
+                }
+            }
+            Option::ProtocolFieldCompression => {
+                if data.is_empty() {
+                    self.pfc_remote = true;
+                    Verdict::Ack
+                } else {
+                    Verdict::Rej
+                }
+            }
+            Option::AddressControlFieldCompression => {
+                if data.is_empty() {
+                    self.acfc_remote = true;
+                    Verdict::Ack
                 } else {
-                    Verdict::Nack(&[0xc0, 0x23])
+                    Verdict::Rej
+                }
+            }
+            Option::FcsAlternatives => {
+                if data.len() != 1 {
+                    return Verdict::Rej;
+                }
+                // We can send a 16-bit or 32-bit FCS, but only one of them.
+                match data[0] {
+                    FCS_16 => Verdict::Ack,
+                    FCS_32 => {
+                        self.fcs32_remote = true;
+                        Verdict::Ack
+                    }
+                    x if x & FCS_32 != 0 => Verdict::Nack(&[FCS_32]),
+                    _ => Verdict::Nack(&[FCS_16]),
                 }
             }
         }
     }
 
     fn own_options(&mut self, mut f: impl FnMut(u8, &[u8])) {
+        // The default MRU doesn't need to be negotiated.
+        if !self.mru_rej && self.mru != DEFAULT_MRU {
+            f(Option::Mru.into(), &self.mru.to_be_bytes());
+        }
         if !self.asyncmap_rej {
             f(Option::Asyncmap.into(), &self.asyncmap.to_be_bytes());
         }
+        if let (Some(period), false) = (self.lqr_period, self.lqr_rej) {
+            let mut data = [0xc0, 0x25, 0, 0, 0, 0];
+            data[2..].copy_from_slice(&period.to_be_bytes());
+            f(Option::QualityProtocol.into(), &data);
+        }
+        if !self.magic_rej {
+            if self.magic == 0 {
+                self.magic = self.random_magic();
+            }
+            if self.magic != 0 {
+                f(Option::MagicNumber.into(), &self.magic.to_be_bytes());
+            }
+        }
+        if !self.pfc_rej {
+            f(Option::ProtocolFieldCompression.into(), &[]);
+        }
+        if !self.acfc_rej {
+            f(Option::AddressControlFieldCompression.into(), &[]);
+        }
+        if self.fcs32 && !self.fcs_rej {
+            f(Option::FcsAlternatives.into(), &[FCS_32]);
+        }
     }
 
     fn own_option_nacked(&mut self, code: u8, data: &[u8], is_rej: bool) {
         let opt = Option::from(code);
         trace!("LCP nak {:?} {:?} {:?} {:?}", code, opt, data, is_rej);
         match opt {
+            Option::Mru => {
+                if is_rej || data.len() != 2 {
+                    self.mru_rej = true;
+                    return;
+                }
+                // We can't receive packets larger than the rx buffer, keep asking for
+                // the largest we can.
+                let mru = u16::from_be_bytes([data[0], data[1]]);
+                self.mru = mru.min(self.mru_max);
+            }
             Option::Asyncmap => {
                 if !is_rej && data.len() == 4 {
                     self.asyncmap = u32::from_be_bytes(data.try_into().unwrap())
@@ -91,7 +389,91 @@ impl Protocol for LCP {
                     self.asyncmap_rej = true
                 }
             }
+            Option::QualityProtocol => {
+                // The peer wants reports at another interval.
+                if !is_rej && data.len() == 6 && data[..2] == [0xc0, 0x25] {
+                    self.lqr_period = Some(u32::from_be_bytes(data[2..].try_into().unwrap()));
+                } else {
+                    self.lqr_rej = true;
+                }
+            }
+            Option::MagicNumber => {
+                if is_rej {
+                    self.magic_rej = true;
+                } else {
+                    // A Nak with a different magic number than the one in the last Nak
+                    // we sent proves the link is not looped back.
+                    if data != &self.magic_nak[..] {
+                        self.loopback_count = 0;
+                    }
+                    self.magic = self.random_magic();
+                }
+            }
+            // These options have no value, a Nak can only mean the peer doesn't want them.
+            Option::ProtocolFieldCompression => self.pfc_rej = true,
+            Option::AddressControlFieldCompression => self.acfc_rej = true,
+            // We only ask for the 32-bit FCS, otherwise keep the default.
+            Option::FcsAlternatives => self.fcs_rej = true,
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MRU: u8 = 1;
+
+    /// The MRU we request, None if the option is not sent.
+    fn requested_mru(lcp: &mut LCP<'_>) -> core::option::Option<u16> {
+        let mut mru = None;
+        lcp.own_options(|code, data| {
+            if code == MRU {
+                mru = Some(u16::from_be_bytes([data[0], data[1]]));
+            }
+        });
+        mru
+    }
+
+    #[test]
+    fn peer_mru() {
+        let mut lcp = LCP::new(None, &Config::default());
+        lcp.peer_options_start();
+
+        let min = MIN_MRU.to_be_bytes();
+        for &mru in &[0u16, 64, MIN_MRU - 1] {
+            let verdict = lcp.peer_option_received(MRU, &mru.to_be_bytes());
+            assert_eq!(verdict, Verdict::Nack(&min));
+        }
+        assert_eq!(lcp.peer_option_received(MRU, &[5]), Verdict::Rej);
+        assert_eq!(lcp.mru_remote, DEFAULT_MRU);
+
+        assert_eq!(lcp.peer_option_received(MRU, &min), Verdict::Ack);
+        assert_eq!(lcp.mru_remote, MIN_MRU);
+        lcp.peer_options_start();
+        assert_eq!(lcp.mru_remote, DEFAULT_MRU);
+    }
+
+    #[test]
+    fn max_mru() {
+        let mut lcp = LCP::new(None, &Config::default());
+        assert_eq!(requested_mru(&mut lcp), None);
+
+        lcp.set_max_mru(1000);
+        assert_eq!(requested_mru(&mut lcp), Some(1000));
+
+        // We can't receive a larger MRU, keep asking for the largest we can.
+        lcp.own_option_nacked(MRU, &2000u16.to_be_bytes(), false);
+        assert_eq!(requested_mru(&mut lcp), Some(1000));
+        lcp.own_option_nacked(MRU, &600u16.to_be_bytes(), false);
+        assert_eq!(requested_mru(&mut lcp), Some(600));
+
+        lcp.own_option_nacked(MRU, &[], true);
+        assert_eq!(requested_mru(&mut lcp), None);
+
+        // A new rx buffer asks again.
+        lcp.set_max_mru(800);
+        assert_eq!(requested_mru(&mut lcp), Some(800));
+    }
+}
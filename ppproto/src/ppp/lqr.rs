@@ -0,0 +1,422 @@
+use crate::fmt::*;
+
+use super::lcp::bad_magic;
+use super::timer::Timer;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{MalformedError, Packet, Payload, ProtocolType};
+
+/// Length of a Link-Quality-Report, without the protocol field.
+const LQR_LEN: usize = 48;
+/// Offset of PeerOutPackets in a Link-Quality-Report.
+const PEER_OUT_PACKETS: usize = 10 * 4;
+
+/// Receive counters kept by the framer, RFC 1989 section 2.2. They wrap around on overflow.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub(crate) struct LinkCounters {
+    /// Frames received with a good FCS.
+    pub in_packets: u32,
+    /// Octets in the frames received with a good FCS, without flags and escapes.
+    pub in_octets: u32,
+    /// Frames dropped because of a bad FCS.
+    pub in_errors: u32,
+    /// Good frames dropped because they didn't fit in the rx buffer.
+    pub in_discards: u32,
+}
+
+/// Packet loss measured with Link-Quality-Reports, over the last reporting period.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkQuality {
+    /// Packets sent by the peer.
+    pub in_packets: u32,
+    /// Packets sent by the peer that we didn't receive, or received with errors.
+    pub in_lost: u32,
+    /// Packets sent to the peer.
+    pub out_packets: u32,
+    /// Packets sent to the peer that it didn't receive, or received with errors.
+    pub out_lost: u32,
+}
+
+impl LinkQuality {
+    /// Returns true if more than `max_loss` percent of the packets were lost in either direction.
+    fn exceeds(&self, max_loss: u8) -> bool {
+        let exceeds =
+            |lost: u32, packets: u32| lost as u64 * 100 > max_loss as u64 * packets as u64;
+        exceeds(self.in_lost, self.in_packets) || exceeds(self.out_lost, self.out_packets)
+    }
+}
+
+/// A received Link-Quality-Report, with the receive counters at the time it arrived.
+#[derive(Copy, Clone, Debug)]
+struct Report {
+    last_out_packets: u32,
+    last_out_lqrs: u32,
+    peer_in_packets: u32,
+    peer_out_lqrs: u32,
+    peer_out_packets: u32,
+    peer_out_octets: u32,
+
+    save_in_lqrs: u32,
+    save: LinkCounters,
+}
+
+/// Fill in PeerOutPackets and PeerOutOctets of a Link-Quality-Report being transmitted.
+///
+/// The framer does it, so the counters include all the frames sent before the report.
+pub(crate) fn set_out_counters(pkt: &mut [u8], out_packets: u32, out_octets: u32) {
+    if pkt.len() >= LQR_LEN {
+        pkt[PEER_OUT_PACKETS..][..4].copy_from_slice(&out_packets.to_be_bytes());
+        pkt[PEER_OUT_PACKETS + 4..][..4].copy_from_slice(&out_octets.to_be_bytes());
+    }
+}
+
+/// Link Quality Monitoring, RFC 1989.
+pub(crate) struct Lqr {
+    timer: Timer,
+    running: bool,
+    /// Interval at which the peer wants our reports. Zero if it only wants them in reply
+    /// to its own, None if it didn't ask for them.
+    period: Option<Duration>,
+
+    out_lqrs: u32,
+    in_lqrs: u32,
+    counters: LinkCounters,
+    last: Option<Report>,
+    quality: Option<LinkQuality>,
+
+    max_loss: Option<u8>,
+}
+
+impl Lqr {
+    pub fn new(config: &Config<'_>) -> Self {
+        Self {
+            timer: Timer::Stopped,
+            running: false,
+            period: None,
+
+            out_lqrs: 0,
+            in_lqrs: 0,
+            counters: LinkCounters::default(),
+            last: None,
+            quality: None,
+
+            max_loss: config.lqr_max_loss,
+        }
+    }
+
+    /// LCP is up. `period` is the Reporting-Period requested by the peer, `requested` is
+    /// true if the peer agreed to send us reports.
+    pub fn start(&mut self, period: Option<Duration>, requested: bool) {
+        self.running = period.is_some() || requested;
+        self.period = period;
+        self.last = None;
+        self.quality = None;
+        if matches!(period, Some(p) if p.total_millis() != 0) {
+            self.timer.start();
+        }
+    }
+
+    /// LCP is down, stop sending reports.
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.timer.stop();
+        self.last = None;
+        self.quality = None;
+    }
+
+    /// Link Quality Monitoring was negotiated in either direction.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Packet loss over the last reporting period, once two reports were received.
+    pub fn quality(&self) -> Option<LinkQuality> {
+        self.quality
+    }
+
+    /// Update the receive counters, before a received packet is handled.
+    pub fn set_counters(&mut self, counters: LinkCounters) {
+        self.counters = counters;
+    }
+
+    /// Send a report when the reporting period expires.
+    pub fn poll(&mut self, now: Instant, magic: u32, tx: impl FnMut(Packet<'_>)) {
+        let period = match self.period {
+            Some(period) if self.running => period,
+            _ => return,
+        };
+        if self.timer.poll(now, period) {
+            self.timer.start();
+            self.send(magic, tx);
+        }
+    }
+
+    /// When the next report is due, if we send them periodically.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer.poll_at()
+    }
+
+    fn send(&mut self, magic: u32, mut tx: impl FnMut(Packet<'_>)) {
+        self.out_lqrs = self.out_lqrs.wrapping_add(1);
+
+        let last = self.last;
+        let field = |f: fn(&Report) -> u32| last.as_ref().map(f).unwrap_or(0);
+        // PeerOutPackets and PeerOutOctets are filled in by the framer.
+        let fields = [
+            magic,
+            field(|r| r.peer_out_lqrs),
+            field(|r| r.peer_out_packets),
+            field(|r| r.peer_out_octets),
+            field(|r| r.save_in_lqrs),
+            field(|r| r.save.in_packets),
+            field(|r| r.save.in_discards),
+            field(|r| r.save.in_errors),
+            field(|r| r.save.in_octets),
+            self.out_lqrs,
+            0,
+            0,
+        ];
+
+        let mut data = [0; LQR_LEN];
+        for (chunk, field) in data.chunks_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        trace!("LQR: tx report {:?}", self.out_lqrs);
+        tx(Packet {
+            proto: ProtocolType::LQR,
+            payload: Payload::Raw(&mut data),
+        });
+    }
+
+    /// Handle a received Link-Quality-Report, including the protocol field.
+    ///
+    /// The report's first field must be the peer's `magic_remote`. Our own `magic` there
+    /// means it's one of our reports looped back. Returns true if the packet loss exceeds
+    /// the configured maximum.
+    pub fn received(
+        &mut self,
+        pkt: &[u8],
+        magic: u32,
+        magic_remote: u32,
+        tx: impl FnMut(Packet<'_>),
+    ) -> Result<bool, MalformedError> {
+        if pkt.len() < 2 + LQR_LEN {
+            return Err(MalformedError::TooShort);
+        }
+        let mut fields = [0; 12];
+        for (field, chunk) in fields.iter_mut().zip(pkt[2..].chunks(4)) {
+            *field = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+
+        if bad_magic(fields[0], magic, magic_remote) {
+            info!("LQR: ignoring report with wrong magic number");
+            return Ok(false);
+        }
+
+        self.in_lqrs = self.in_lqrs.wrapping_add(1);
+        let report = Report {
+            last_out_lqrs: fields[1],
+            last_out_packets: fields[2],
+            peer_in_packets: fields[5],
+            peer_out_lqrs: fields[9],
+            peer_out_packets: fields[10],
+            peer_out_octets: fields[11],
+
+            save_in_lqrs: self.in_lqrs,
+            save: self.counters,
+        };
+
+        let mut exceeded = false;
+        if let Some(prev) = self.last {
+            let quality = Self::compute(&prev, &report);
+            info!("LQR: {:?}", quality);
+            self.quality = Some(quality);
+            if let Some(max_loss) = self.max_loss {
+                exceeded = quality.exceeds(max_loss);
+            }
+        }
+        self.last = Some(report);
+
+        // Without a timer of our own, reply to each report so the peer can measure too.
+        if !matches!(self.period, Some(p) if p.total_millis() != 0) {
+            self.send(magic, tx);
+        }
+        Ok(exceeded)
+    }
+
+    /// Packet loss between two consecutive reports, RFC 1989 section 2.7.
+    fn compute(prev: &Report, cur: &Report) -> LinkQuality {
+        let in_packets = cur.peer_out_packets.wrapping_sub(prev.peer_out_packets);
+        let in_received = cur.save.in_packets.wrapping_sub(prev.save.in_packets);
+
+        // The outbound counters are only valid once the peer received one of our reports.
+        let (out_packets, out_received) = if prev.last_out_lqrs != 0 {
+            (
+                cur.last_out_packets.wrapping_sub(prev.last_out_packets),
+                cur.peer_in_packets.wrapping_sub(prev.peer_in_packets),
+            )
+        } else {
+            (0, 0)
+        };
+
+        LinkQuality {
+            in_packets,
+            in_lost: in_packets.saturating_sub(in_received),
+            out_packets,
+            out_lost: out_packets.saturating_sub(out_received),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counters of one report: the peer's and ours when it arrives.
+    #[derive(Copy, Clone)]
+    struct Counts {
+        /// Reports the peer received from us, and our packets it had received.
+        last_out_lqrs: u32,
+        last_out_packets: u32,
+        peer_in_packets: u32,
+        /// Packets the peer sent, and the ones we received.
+        peer_out_packets: u32,
+        in_packets: u32,
+    }
+
+    impl Counts {
+        fn offset(self, offset: u32) -> Self {
+            Self {
+                last_out_lqrs: self.last_out_lqrs,
+                last_out_packets: self.last_out_packets.wrapping_add(offset),
+                peer_in_packets: self.peer_in_packets.wrapping_add(offset),
+                peer_out_packets: self.peer_out_packets.wrapping_add(offset),
+                in_packets: self.in_packets.wrapping_add(offset),
+            }
+        }
+    }
+
+    /// Receive a report from the peer with `counts`, returning whether the loss is
+    /// exceeded.
+    fn receive(lqr: &mut Lqr, counts: Counts) -> bool {
+        let mut fields = [0u32; 12];
+        fields[1] = counts.last_out_lqrs;
+        fields[2] = counts.last_out_packets;
+        fields[5] = counts.peer_in_packets;
+        fields[9] = counts.last_out_lqrs;
+        fields[10] = counts.peer_out_packets;
+        let mut pkt = [0; 2 + LQR_LEN];
+        pkt[..2].copy_from_slice(&[0xc0, 0x25]);
+        for (chunk, field) in pkt[2..].chunks_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+
+        lqr.set_counters(LinkCounters {
+            in_packets: counts.in_packets,
+            ..LinkCounters::default()
+        });
+        unwrap!(lqr.received(&pkt, 0, 0, |_| {}))
+    }
+
+    fn lqr(max_loss: Option<u8>) -> Lqr {
+        let mut lqr = Lqr::new(&Config {
+            lqr_max_loss: max_loss,
+            ..Config::default()
+        });
+        lqr.start(Some(Duration::from_secs(10)), true);
+        lqr
+    }
+
+    const FIRST: Counts = Counts {
+        last_out_lqrs: 1,
+        last_out_packets: 100,
+        peer_in_packets: 90,
+        peer_out_packets: 200,
+        in_packets: 195,
+    };
+    /// The peer sent 100 packets and we received 90, we sent 100 and it received 95.
+    const SECOND: Counts = Counts {
+        last_out_lqrs: 2,
+        last_out_packets: 200,
+        peer_in_packets: 185,
+        peer_out_packets: 300,
+        in_packets: 285,
+    };
+    const QUALITY: LinkQuality = LinkQuality {
+        in_packets: 100,
+        in_lost: 10,
+        out_packets: 100,
+        out_lost: 5,
+    };
+
+    #[test]
+    fn loss() {
+        let mut lqr = lqr(None);
+        assert!(!receive(&mut lqr, FIRST));
+        assert_eq!(lqr.quality(), None);
+        assert!(!receive(&mut lqr, SECOND));
+        assert_eq!(lqr.quality(), Some(QUALITY));
+
+        // Until the peer received one of our reports, only the inbound loss is known.
+        let mut lqr = self::lqr(None);
+        let first = Counts {
+            last_out_lqrs: 0,
+            ..FIRST
+        };
+        receive(&mut lqr, first);
+        receive(&mut lqr, SECOND);
+        let quality = LinkQuality {
+            out_packets: 0,
+            out_lost: 0,
+            ..QUALITY
+        };
+        assert_eq!(lqr.quality(), Some(quality));
+    }
+
+    #[test]
+    fn counter_wraparound() {
+        for &offset in &[u32::MAX - 150, u32::MAX - 250, u32::MAX] {
+            let mut lqr = lqr(None);
+            receive(&mut lqr, FIRST.offset(offset));
+            receive(&mut lqr, SECOND.offset(offset));
+            assert_eq!(lqr.quality(), Some(QUALITY));
+        }
+    }
+
+    #[test]
+    fn max_loss() {
+        // 10% lost inbound is not more than 10%.
+        let mut lqr = lqr(Some(10));
+        receive(&mut lqr, FIRST);
+        assert!(!receive(&mut lqr, SECOND));
+
+        // 11% lost outbound is.
+        let third = Counts {
+            last_out_lqrs: 3,
+            last_out_packets: 300,
+            peer_in_packets: 274,
+            peer_out_packets: 400,
+            in_packets: 385,
+        };
+        assert!(receive(&mut lqr, third));
+
+        // Without a maximum the loss is only measured.
+        let mut lqr = self::lqr(None);
+        receive(&mut lqr, SECOND);
+        assert!(!receive(&mut lqr, third));
+    }
+
+    #[test]
+    fn out_counters() {
+        let mut pkt = [0; LQR_LEN];
+        set_out_counters(&mut pkt, 0x0102_0304, 0x0506_0708);
+        assert_eq!(pkt[40..], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(pkt[..40].iter().all(|&b| b == 0));
+
+        // Not a report, left alone.
+        let mut pkt = [0; LQR_LEN - 1];
+        set_out_counters(&mut pkt, 1, 1);
+        assert!(pkt.iter().all(|&b| b == 0));
+    }
+}
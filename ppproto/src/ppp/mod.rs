@@ -1,22 +1,121 @@
+mod chap;
+mod eap;
+mod echo;
+mod ident;
 mod ipv4cp;
 mod lcp;
+mod lqr;
+mod mschapv2;
 mod option_fsm;
 mod pap;
+mod timer;
 
-use crate::fmt::{panic, todo, *};
-use core::convert::TryInto;
+use crate::fmt::{panic, *};
 
+use self::chap::{State as CHAPState, CHAP};
+use self::eap::{State as EAPState, EAP};
+use self::echo::Echo;
+use self::ident::Ident;
 use self::ipv4cp::IPv4CP;
 use self::lcp::{AuthType, LCP};
-use self::option_fsm::{OptionFsm, State};
+use self::lqr::Lqr;
+use self::option_fsm::{LayerEvent, OptionFsm, Protocol, State};
 use self::pap::{State as PAPState, PAP};
-use crate::wire::{Packet, ProtocolType};
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, MalformedError, Packet, ProtocolType};
 
+#[cfg(feature = "rustls")]
+pub use self::eap::RustlsBackend;
+pub use self::eap::{EapError, EapMethod, EapTls, TlsBackend};
+pub use self::ident::Identification;
 pub use self::ipv4cp::Ipv4Status;
+pub use self::lqr::LinkQuality;
+pub(crate) use self::lqr::{set_out_counters, LinkCounters};
+pub use self::mschapv2::MsChapFailure;
+
+/// Source of random numbers.
+///
+/// Used to pick the LCP magic number, which must differ between the two ends of the link.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+}
 
 pub struct Config<'a> {
     pub username: &'a [u8],
     pub password: &'a [u8],
+
+    /// Message sent to the peer in an LCP Identification packet when LCP goes up, such
+    /// as the firmware name and version. Empty to not send one.
+    pub identification: &'a [u8],
+
+    /// Characters to escape when transmitting, in addition to the control characters
+    /// in the peer's Async-Control-Character-Map. Useful on links that use 0x91/0x93
+    /// for flow control, or that mangle some characters. 0x20-0x3f and 0x5e can't be
+    /// escaped, `PPPoS::new` panics if one of them is in the list.
+    pub escape: &'a [u8],
+
+    /// Request the peer to send frames with a 32-bit FCS, with the FCS-Alternatives
+    /// option (RFC 1570). The 16-bit FCS is used if the peer doesn't agree.
+    pub fcs32: bool,
+
+    /// EAP methods offered to the peer, in addition to the built-in MD5-Challenge, such
+    /// as EAP-TLS. Only used if the peer asks for EAP authentication.
+    pub eap_methods: &'a mut [&'a mut dyn EapMethod],
+    /// Buffer for our EAP Responses, which are at most its length plus the 4-byte header.
+    /// EAP-TLS needs one as large as the peer's MRU minus the header, such as 1496 bytes,
+    /// to send full fragments. If empty, a 122 byte built-in one is used.
+    pub eap_buf: &'a mut [u8],
+
+    /// Random source for the LCP magic number. If None, the Magic-Number option is not
+    /// requested and looped back links can't be detected.
+    pub rng: Option<&'a mut dyn Rng>,
+
+    /// Restart timer interval, after which unanswered Configure-Request,
+    /// Terminate-Request and Authenticate-Request packets are retransmitted.
+    pub restart_interval: Duration,
+    /// Max-Configure: Configure-Requests sent without reply before giving up.
+    pub max_configure: u8,
+    /// Max-Terminate: Terminate-Requests sent without reply before giving up.
+    pub max_terminate: u8,
+    /// Max-Failure: Configure-Naks of an option sent without an Ack before rejecting it
+    /// instead. The link fails if the peer requests it again after Max-Failure rejects.
+    pub max_failure: u8,
+
+    /// Interval between LCP Echo-Requests sent while the link is up. None disables them.
+    pub echo_interval: Option<Duration>,
+    /// Echo-Requests sent without reply before the link is considered dead.
+    pub echo_failure: u8,
+
+    /// Interval at which the peer is asked to send Link-Quality-Reports (RFC 1989).
+    /// None to not ask for them. Reports are sent to the peer when it asks for them.
+    pub lqr_interval: Option<Duration>,
+    /// Take the link down when more than this percentage of the packets is lost in
+    /// either direction over a reporting period. None to only measure the loss.
+    pub lqr_max_loss: Option<u8>,
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        // Defaults suggested in RFC 1661 section 4.6
+        Self {
+            username: &[],
+            password: &[],
+            identification: &[],
+            escape: &[],
+            fcs32: false,
+            eap_methods: &mut [],
+            eap_buf: &mut [],
+            rng: None,
+            restart_interval: Duration::from_secs(3),
+            max_configure: 10,
+            max_terminate: 2,
+            max_failure: 5,
+            echo_interval: None,
+            echo_failure: 3,
+            lqr_interval: None,
+            lqr_max_loss: None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Ord, PartialOrd)]
@@ -27,115 +126,1048 @@ pub enum Phase {
     Auth,
     Network,
     Open,
+    Terminate,
+}
+
+/// Reason why the link failed.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// LCP negotiation did not complete after Max-Configure Configure-Requests.
+    LcpTimeout,
+    /// No reply to Max-Configure Authenticate-Requests, or no CHAP Success from the
+    /// peer in the same time.
+    AuthTimeout,
+    /// IPv4CP negotiation did not complete after Max-Configure Configure-Requests.
+    Ipv4cpTimeout,
+    /// The peer rejected an LCP code needed to negotiate or terminate the link.
+    LcpRejected,
+    /// The peer sent a Protocol-Reject for the authentication protocol.
+    AuthRejected,
+    /// The peer refused our credentials with a CHAP or EAP Failure, or an EAP method failed.
+    AuthFailed,
+    /// The peer didn't prove its identity: the MS-CHAPv2 Success message was wrong, or
+    /// an EAP method returned `EapError::PeerInvalid`.
+    AuthPeerInvalid,
+    /// The peer didn't reply to `echo_failure` Echo-Requests.
+    EchoTimeout,
+    /// Our own LCP magic number came back Max-Failure times in a row.
+    LoopedBack,
+    /// The peer kept requesting LCP options after they were rejected Max-Failure times.
+    LcpNotConverging,
+    /// The peer kept requesting IPv4CP options after they were rejected Max-Failure times.
+    Ipv4cpNotConverging,
+    /// The packet loss measured with Link-Quality-Reports exceeded `lqr_max_loss`.
+    LinkQuality,
 }
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Status {
+    /// Current link phase.
+    pub phase: Phase,
+    /// Why the link last failed. Cleared when the link is opened again.
+    pub failure: Option<Failure>,
     /// IPv4 configuration obtained from IPv4CP. None if IPv4CP is not up.
     pub ipv4: Option<Ipv4Status>,
+    /// The peer rejected IPv4CP, so IPv4 is unavailable on this link.
+    pub ipv4_rejected: bool,
+    /// Largest IP packet that can be sent: the peer's MRU. None if LCP is not up.
+    pub mtu: Option<u16>,
+    /// Message of the peer's LCP Identification packet, if it sent one.
+    pub peer_identification: Option<Identification>,
+    /// Instant at which the peer will disconnect, as announced in an LCP Time-Remaining
+    /// packet. None if LCP is not up or the peer didn't announce it.
+    pub disconnect_at: Option<Instant>,
+    /// Packet loss measured with Link-Quality-Reports. None until two reports were received.
+    pub link_quality: Option<LinkQuality>,
+    /// Error reported by the peer when it refused our MS-CHAPv2 credentials. Cleared
+    /// when authentication starts again.
+    pub ms_chap_failure: Option<MsChapFailure>,
+    /// Type of the EAP method the peer authenticates us with, such as 4 for MD5-Challenge
+    /// or 13 for EAP-TLS. None if no method was started.
+    pub eap_method: Option<u8>,
+    /// Why the EAP method failed, if it did.
+    pub eap_error: Option<EapError>,
+    /// Link statistics.
+    pub counters: Counters,
+    /// Why the last malformed packet was dropped.
+    pub last_malformed: Option<MalformedError>,
+}
+
+/// Link statistics. All counters wrap around on overflow.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Counters {
+    /// Configure-Ack/Nak/Rej discarded because their Identifier or options didn't
+    /// match the outstanding Configure-Request.
+    pub discarded_replies: u32,
+    /// Received packets dropped because they were malformed.
+    pub malformed_packets: u32,
+    /// Received frames dropped because they didn't fit in the rx buffer.
+    pub oversized_frames: u32,
+    /// Received control characters dropped because they were not escaped, but are
+    /// in the receive Async-Control-Character-Map. Usually line noise or flow control.
+    pub discarded_chars: u32,
 }
 
 pub struct PPP<'a> {
     phase: Phase,
-    pub(crate) lcp: OptionFsm<LCP>,
+    failure: Option<Failure>,
+    lower_up: bool,
+    /// LCP went up while handling a received packet. The upper layers are started
+    /// on the next `poll`, after the packet that changed the framing options.
+    lcp_up: bool,
+    malformed_packets: u32,
+    last_malformed: Option<MalformedError>,
+    /// Longest packet the lower layer can send, without the protocol field.
+    max_tx_len: usize,
+    pub(crate) lcp: OptionFsm<LCP<'a>>,
+    echo: Echo,
+    ident: Ident<'a>,
+    lqr: Lqr,
     pub(crate) pap: PAP<'a>,
+    pub(crate) chap: CHAP<'a>,
+    pub(crate) eap: EAP<'a>,
     pub(crate) ipv4cp: OptionFsm<IPv4CP>,
 }
 
 impl<'a> PPP<'a> {
-    pub fn new(config: Config<'a>) -> Self {
+    pub fn new(mut config: Config<'a>) -> Self {
+        let rng = config.rng.take();
+        let eap_methods = core::mem::take(&mut config.eap_methods);
+        let eap_buf = core::mem::take(&mut config.eap_buf);
         Self {
             phase: Phase::Dead,
-            lcp: OptionFsm::new(LCP::new()),
-            pap: PAP::new(config.username, config.password),
-            ipv4cp: OptionFsm::new(IPv4CP::new()),
+            failure: None,
+            lower_up: true,
+            lcp_up: false,
+            malformed_packets: 0,
+            last_malformed: None,
+            max_tx_len: usize::MAX,
+            lcp: OptionFsm::new(LCP::new(rng, &config), &config),
+            echo: Echo::new(&config),
+            ident: Ident::new(&config),
+            lqr: Lqr::new(&config),
+            pap: PAP::new(&config),
+            chap: CHAP::new(&config),
+            eap: EAP::new(&config, eap_methods, eap_buf),
+            ipv4cp: OptionFsm::new(IPv4CP::new(), &config),
         }
     }
 
     pub fn status(&self) -> Status {
         Status {
+            phase: self.phase,
+            failure: self.failure,
             ipv4: if self.ipv4cp.state() == State::Opened {
                 Some(self.ipv4cp.proto().status())
             } else {
                 None
             },
+            ipv4_rejected: self.ipv4cp.rejected(),
+            mtu: if self.lcp.state() == State::Opened {
+                Some(self.lcp.proto().mru_remote)
+            } else {
+                None
+            },
+            peer_identification: self.ident.peer(),
+            disconnect_at: self.ident.disconnect_at(),
+            link_quality: self.lqr.quality(),
+            ms_chap_failure: self.chap.ms_chap_failure(),
+            eap_method: self.eap.method(),
+            eap_error: self.eap.error(),
+            counters: Counters {
+                discarded_replies: self.lcp.discarded().wrapping_add(self.ipv4cp.discarded()),
+                malformed_packets: self.malformed_packets,
+                oversized_frames: 0,
+                discarded_chars: 0,
+            },
+            last_malformed: self.last_malformed,
         }
     }
 
     pub fn open(&mut self) -> Result<(), crate::InvalidStateError> {
         match self.phase {
             Phase::Dead => {
-                self.phase = Phase::Establish;
+                self.failure = None;
+                self.lcp.proto_mut().reset_magic();
+                self.ident.reset();
+                // If LCP is Starting, the link is already open and waiting for the lower layer.
+                if self.lcp.state() != State::Starting {
+                    self.set_phase(Phase::Establish);
+                }
                 Ok(())
             }
             _ => Err(crate::InvalidStateError),
         }
     }
 
-    pub fn received(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
-        let proto = u16::from_be_bytes(pkt[0..2].try_into().unwrap());
+    pub fn ping(&mut self) -> Result<(), crate::InvalidStateError> {
+        if self.lcp.state() != State::Opened {
+            return Err(crate::InvalidStateError);
+        }
+        self.echo.ping();
+        Ok(())
+    }
 
-        match proto.into() {
-            ProtocolType::LCP => self.lcp.handle(pkt, &mut tx),
-            ProtocolType::PAP => self.pap.handle(pkt, &mut tx),
-            ProtocolType::IPv4 => todo!(),
-            ProtocolType::IPv4CP => self.ipv4cp.handle(pkt, &mut tx),
-            ProtocolType::Unknown => tx(self.lcp.send_protocol_reject(pkt)),
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.echo.ping_rtt()
+    }
+
+    /// Receive ACCM: control characters the peer must escape.
+    ///
+    /// All of them until LCP is up, then the Async-Control-Character-Map we requested.
+    pub fn rx_asyncmap(&self) -> u32 {
+        let lcp = self.lcp.proto();
+        if self.lcp.state() == State::Opened && !lcp.asyncmap_rej {
+            lcp.asyncmap
+        } else {
+            0xFFFFFFFF
         }
     }
 
-    pub fn poll(&mut self, mut tx: impl FnMut(Packet<'_>)) {
-        // TODO this state machine can probably be written in nicer way.
-        // TODO this is probably not rfc compliant, check what other impls do
-        let old_phase = self.phase;
+    /// MPPE master key derived from the MS-CHAPv2 authentication, RFC 3079 section 3.4.
+    pub fn master_key(&self) -> Option<[u8; 16]> {
+        self.chap.master_key()
+    }
+
+    /// Master Session Key derived by the EAP method, such as EAP-TLS.
+    pub fn eap_msk(&self) -> Option<[u8; 64]> {
+        self.eap.msk()
+    }
+
+    /// Update the receive counters of the framer, used in Link-Quality-Reports.
+    ///
+    /// Must be called before `received`, so the counters include the received packet.
+    pub(crate) fn set_link_counters(&mut self, counters: LinkCounters) {
+        self.lqr.set_counters(counters);
+    }
+
+    /// Limit the length of the packets sent, without the protocol field. Only the EAP
+    /// Responses can be longer than a control packet.
+    ///
+    /// Must be called before `received`, with the space left to send the Response.
+    pub(crate) fn set_max_tx_len(&mut self, len: usize) {
+        self.max_tx_len = len;
+    }
+
+    /// The peer must send a 32-bit FCS. Only once LCP is up.
+    pub fn rx_fcs32(&self) -> bool {
+        let lcp = self.lcp.proto();
+        self.lcp.state() == State::Opened && lcp.fcs32 && !lcp.fcs_rej
+    }
+
+    /// We must send a 32-bit FCS. Only once LCP is up.
+    pub fn tx_fcs32(&self) -> bool {
+        self.lcp.state() == State::Opened && self.lcp.proto().fcs32_remote
+    }
+
+    pub fn close(&mut self) -> Result<(), crate::InvalidStateError> {
         match self.phase {
-            Phase::Dead => {}
-            Phase::Establish => {
-                if self.lcp.state() == State::Closed {
-                    tx(self.lcp.open());
-                }
-
-                if self.lcp.state() == State::Opened {
-                    match self.lcp.proto().auth {
-                        AuthType::None => {
-                            tx(self.ipv4cp.open());
-                            self.phase = Phase::Network;
-                        }
-                        AuthType::PAP => {
-                            tx(self.pap.open());
-                            self.phase = Phase::Auth;
-                        }
+            Phase::Dead if self.lcp.state() != State::Starting => Err(crate::InvalidStateError),
+            _ => {
+                self.set_phase(Phase::Terminate);
+                Ok(())
+            }
+        }
+    }
+
+    /// Lower layer is Up. LCP negotiation starts on the next poll if the link is open.
+    pub fn lower_up(&mut self) {
+        self.lower_up = true;
+    }
+
+    /// Lower layer is Down. All protocols are taken down, and the link goes to Dead.
+    pub fn lower_down(&mut self) {
+        self.lower_up = false;
+        let closing = self.phase == Phase::Terminate;
+        let event = self.lcp.down();
+        // This-Layer-Down never transmits anything.
+        self.lcp_event(event);
+        self.close_auth();
+        // LCP may still be waiting for the NCPs to close. Close it now, or the link
+        // would be established again when the lower layer comes back up.
+        if closing {
+            let event = self.lcp.close(|_| {});
+            self.lcp_event(event);
+        }
+        self.set_phase(Phase::Dead);
+    }
+
+    /// Handle a received packet, including the protocol field.
+    ///
+    /// Malformed packets are dropped and counted, and the error is returned.
+    pub fn received(
+        &mut self,
+        now: Instant,
+        pkt: &mut [u8],
+        tx: impl FnMut(Packet<'_>),
+    ) -> Result<(), MalformedError> {
+        let res = self.handle_received(now, pkt, tx);
+        if let Err(e) = res {
+            info!("dropping malformed packet: {:?}", e);
+            self.malformed_packets = self.malformed_packets.wrapping_add(1);
+            self.last_malformed = Some(e);
+        }
+        res
+    }
+
+    fn handle_received(
+        &mut self,
+        now: Instant,
+        pkt: &mut [u8],
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<(), MalformedError> {
+        if pkt.len() < 2 {
+            return Err(MalformedError::TooShort);
+        }
+        let proto = u16::from_be_bytes([pkt[0], pkt[1]]);
+
+        match proto.into() {
+            ProtocolType::LCP => {
+                // Read the code first: an Echo-Request is turned into our Echo-Reply in place.
+                let code = Code::from(*pkt.get(2).ok_or(MalformedError::TooShort)?);
+                let event = self.lcp.handle(pkt, &mut tx)?;
+                if self.lcp.rejected() && self.failure.is_none() {
+                    info!("PPP link failed: {:?}", Failure::LcpRejected);
+                    self.failure = Some(Failure::LcpRejected);
+                }
+                self.lcp_event(event);
+                if self.failure.is_none() {
+                    if self.lcp.proto().looped_back() {
+                        self.fail(Failure::LoopedBack, &mut tx);
+                    } else if self.lcp.not_converging() {
+                        self.fail(Failure::LcpNotConverging, &mut tx);
                     }
-                } else {
-                    if self.pap.state() != PAPState::Closed {
-                        self.pap.close();
+                }
+
+                if pkt.len() >= 8 && code == Code::ProtocolRej && self.lcp.state() == State::Opened
+                {
+                    let proto = u16::from_be_bytes([pkt[6], pkt[7]]);
+                    self.protocol_rejected(proto, &mut tx);
+                }
+                let lcp = self.lcp.proto();
+                match code {
+                    Code::EchoReply if self.lcp.state() == State::Opened => {
+                        self.echo
+                            .received_reply(now, pkt, lcp.magic_number(), lcp.magic_remote);
+                    }
+                    // Identification may be sent before LCP is up.
+                    Code::Identification => {
+                        self.ident
+                            .received(now, pkt, lcp.magic_number(), lcp.magic_remote)?;
                     }
-                    if self.ipv4cp.state() != State::Closed {
-                        self.ipv4cp.close();
+                    Code::TimeRemaining if self.lcp.state() == State::Opened => {
+                        self.ident
+                            .received(now, pkt, lcp.magic_number(), lcp.magic_remote)?;
                     }
+                    _ => {}
+                }
+            }
+            ProtocolType::PAP => self.pap.handle(pkt, &mut tx)?,
+            ProtocolType::CHAP => {
+                self.chap.handle(pkt, self.lcp.proto_mut().rng(), &mut tx)?;
+                let failure = match self.chap.state() {
+                    CHAPState::Failed => Some(Failure::AuthFailed),
+                    CHAPState::BadSuccess => Some(Failure::AuthPeerInvalid),
+                    _ => None,
+                };
+                if let Some(failure) = failure {
+                    self.chap.close();
+                    self.fail(failure, &mut tx);
+                }
+            }
+            ProtocolType::EAP => {
+                let max_len = (self.lcp.proto().mru_remote as usize).min(self.max_tx_len);
+                self.eap.handle(pkt, max_len, &mut tx)?;
+                if self.eap.state() == EAPState::Failed {
+                    let failure = match self.eap.error() {
+                        Some(EapError::PeerInvalid) => Failure::AuthPeerInvalid,
+                        _ => Failure::AuthFailed,
+                    };
+                    self.eap.close();
+                    self.fail(failure, &mut tx);
+                }
+            }
+            ProtocolType::LQR if self.lqr.is_running() => {
+                let lcp = self.lcp.proto();
+                let exceeded =
+                    self.lqr
+                        .received(pkt, lcp.magic_number(), lcp.magic_remote, &mut tx)?;
+                if exceeded && self.failure.is_none() {
+                    self.fail(Failure::LinkQuality, &mut tx);
+                }
+            }
+            // IPv4 packets are passed to the application by PPPoS, they never get here.
+            ProtocolType::IPv4 => {}
+            ProtocolType::IPv4CP => {
+                let event = self.ipv4cp.handle(pkt, &mut tx)?;
+                self.ipv4cp_event(event);
+                if self.ipv4cp.not_converging() && self.failure.is_none() {
+                    self.fail(Failure::Ipv4cpNotConverging, &mut tx);
+                }
+            }
+            ProtocolType::LQR | ProtocolType::Unknown => tx(self.lcp.send_protocol_reject(pkt)),
+        }
+        Ok(())
+    }
+
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        self.poll_timers(now, &mut tx);
+
+        if self.lcp_up {
+            self.lcp_up = false;
+            self.ident.send(self.lcp.proto().magic_number(), &mut tx);
+            match self.lcp.proto().auth {
+                AuthType::None => self.start_network(&mut tx),
+                AuthType::PAP => {
+                    tx(self.pap.open());
+                    self.set_phase(Phase::Auth);
+                }
+                AuthType::CHAP(algorithm) => {
+                    self.chap.open(algorithm);
+                    self.set_phase(Phase::Auth);
+                }
+                AuthType::EAP => {
+                    self.eap.open();
+                    self.set_phase(Phase::Auth);
+                }
+            }
+        }
+
+        match self.phase {
+            Phase::Establish => {
+                // Administrative Open
+                if matches!(
+                    self.lcp.state(),
+                    State::Initial | State::Closed | State::Stopped
+                ) {
+                    let event = self.lcp.open(&mut tx);
+                    self.lcp_event(event);
                 }
             }
-            Phase::Auth => {
-                if self.pap.state() == PAPState::Opened {
-                    self.phase = Phase::Network;
-                    tx(self.ipv4cp.open());
+            Phase::Auth
+                if self.pap.state() == PAPState::Opened
+                    || self.chap.state() == CHAPState::Opened
+                    || self.eap.state() == EAPState::Opened =>
+            {
+                self.start_network(&mut tx)
+            }
+            Phase::Terminate => self.terminate(&mut tx),
+            _ => {}
+        }
+
+        // LCP is open and waiting for the lower layer.
+        if self.lcp.state() == State::Starting {
+            if self.lower_up {
+                self.set_phase(Phase::Establish);
+                let event = self.lcp.up(&mut tx);
+                self.lcp_event(event);
+            } else {
+                self.set_phase(Phase::Dead);
+            }
+        }
+
+        // Arm the timers started above.
+        self.poll_timers(now, &mut tx);
+    }
+
+    fn poll_timers(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        let negotiating = self.lcp.is_negotiating();
+        let event = self.lcp.poll(now, &mut tx);
+        if negotiating && event == Some(LayerEvent::Finished) {
+            self.fail(Failure::LcpTimeout, &mut tx);
+        }
+        self.lcp_event(event);
+
+        if self
+            .echo
+            .poll(now, self.lcp.proto().magic_number(), &mut tx)
+        {
+            self.fail(Failure::EchoTimeout, &mut tx);
+        }
+
+        self.lqr.poll(now, self.lcp.proto().magic_number(), &mut tx);
+
+        self.pap.poll(now, &mut tx);
+        if self.pap.state() == PAPState::Failed {
+            self.pap.close();
+            self.fail(Failure::AuthTimeout, &mut tx);
+        }
+
+        self.chap.poll(now);
+        if self.chap.state() == CHAPState::TimedOut {
+            self.chap.close();
+            self.fail(Failure::AuthTimeout, &mut tx);
+        }
+
+        self.eap.poll(now);
+        if self.eap.state() == EAPState::TimedOut {
+            self.eap.close();
+            self.fail(Failure::AuthTimeout, &mut tx);
+        }
+
+        let negotiating = self.ipv4cp.is_negotiating();
+        let event = self.ipv4cp.poll(now, &mut tx);
+        if negotiating && event == Some(LayerEvent::Finished) {
+            self.fail(Failure::Ipv4cpTimeout, &mut tx);
+        }
+        self.ipv4cp_event(event);
+    }
+
+    /// The earliest timer of all the protocols, or now if an Open, Close or phase
+    /// change is waiting to be processed.
+    pub fn poll_at(&self) -> Option<Instant> {
+        // Administrative Open and Close are processed in poll.
+        let lcp_state = self.lcp.state();
+        let pending = match self.phase {
+            Phase::Establish => matches!(
+                lcp_state,
+                State::Initial | State::Starting | State::Closed | State::Stopped
+            ),
+            // Once the NCPs or LCP are closing, their restart timers drive the termination.
+            Phase::Terminate => {
+                !matches!(self.ipv4cp.state(), State::Closing | State::Stopping)
+                    && !matches!(lcp_state, State::Closing | State::Stopping)
+            }
+            _ => false,
+        } || (self.lower_up && lcp_state == State::Starting)
+            || self.lcp_up;
+        if pending {
+            return Some(Instant::ZERO);
+        }
+
+        let timers = [
+            self.lcp.poll_at(),
+            self.echo.poll_at(),
+            self.lqr.poll_at(),
+            self.pap.poll_at(),
+            self.chap.poll_at(),
+            self.eap.poll_at(),
+            self.ipv4cp.poll_at(),
+        ];
+        timers.iter().flatten().min().copied()
+    }
+
+    /// Record a failure and terminate the link.
+    fn fail(&mut self, failure: Failure, tx: impl FnMut(Packet<'_>)) {
+        info!("PPP link failed: {:?}", failure);
+        self.failure = Some(failure);
+        self.set_phase(Phase::Terminate);
+        self.terminate(tx);
+    }
+
+    /// Link Termination phase: close the NCPs, then close LCP once they're done.
+    fn terminate(&mut self, mut tx: impl FnMut(Packet<'_>)) {
+        let event = self.ipv4cp.close(&mut tx);
+        self.ipv4cp_event(event);
+        self.close_auth();
+
+        // If the peer is terminating LCP already, just let it finish.
+        let ncps_closing = matches!(self.ipv4cp.state(), State::Closing | State::Stopping);
+        if !ncps_closing && self.lcp.state() != State::Stopping {
+            let event = self.lcp.close(&mut tx);
+            self.lcp_event(event);
+        }
+
+        // LCP was not running, there's nothing to wait for.
+        if matches!(
+            self.lcp.state(),
+            State::Initial | State::Starting | State::Closed | State::Stopped
+        ) {
+            self.set_phase(Phase::Dead);
+        }
+    }
+
+    /// Stop the authentication protocols.
+    fn close_auth(&mut self) {
+        if self.pap.state() != PAPState::Closed {
+            self.pap.close();
+        }
+        if self.chap.state() != CHAPState::Closed {
+            self.chap.close();
+        }
+        if self.eap.state() != EAPState::Closed {
+            self.eap.close();
+        }
+    }
+
+    fn protocol_rejected(&mut self, proto: u16, tx: impl FnMut(Packet<'_>)) {
+        match proto.into() {
+            ProtocolType::IPv4 | ProtocolType::IPv4CP => {
+                info!("IPv4 rejected by peer");
+                let event = self.ipv4cp.protocol_rejected(tx);
+                self.ipv4cp_event(event);
+            }
+            ProtocolType::PAP | ProtocolType::CHAP | ProtocolType::EAP => {
+                self.fail(Failure::AuthRejected, tx)
+            }
+            x => info!("ignoring Protocol-Reject for {:?}", x),
+        }
+    }
+
+    fn lcp_event(&mut self, event: Option<LayerEvent>) {
+        match event {
+            Some(LayerEvent::Up) => {
+                self.echo.start();
+                let lcp = self.lcp.proto();
+                let period = lcp.lqr_remote.map(|p| Duration::from_millis(p as u64 * 10));
+                self.lqr
+                    .start(period, lcp.lqr_period.is_some() && !lcp.lqr_rej);
+                self.lcp_up = true;
+            }
+            Some(LayerEvent::Down) => {
+                self.echo.stop();
+                self.ident.stop();
+                self.lqr.stop();
+                self.lcp_up = false;
+                self.close_auth();
+                let event = self.ipv4cp.down();
+                self.ipv4cp_event(event);
+                if matches!(self.lcp.state(), State::Closing | State::Stopping) {
+                    self.set_phase(Phase::Terminate);
                 } else {
-                    if self.ipv4cp.state() != State::Closed {
-                        self.ipv4cp.close();
-                    }
+                    self.set_phase(Phase::Establish);
+                }
+            }
+            Some(LayerEvent::Finished) => self.set_phase(Phase::Dead),
+            Some(LayerEvent::Started) | None => {}
+        }
+    }
+
+    fn ipv4cp_event(&mut self, event: Option<LayerEvent>) {
+        match event {
+            Some(LayerEvent::Up) => self.set_phase(Phase::Open),
+            Some(LayerEvent::Down) => {
+                if self.phase == Phase::Open {
+                    self.set_phase(Phase::Network)
                 }
             }
-            Phase::Network => {
-                if self.ipv4cp.state() == State::Opened {
-                    self.phase = Phase::Open;
+            Some(LayerEvent::Finished) => info!("IPv4CP finished"),
+            Some(LayerEvent::Started) | None => {}
+        }
+    }
+
+    fn start_network(&mut self, mut tx: impl FnMut(Packet<'_>)) {
+        self.set_phase(Phase::Network);
+        let event = self.ipv4cp.open(&mut tx);
+        self.ipv4cp_event(event);
+        if self.ipv4cp.state() == State::Starting {
+            let event = self.ipv4cp.up(&mut tx);
+            self.ipv4cp_event(event);
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        if self.phase != phase {
+            info!("PPP link phase {:?} -> {:?}", self.phase, phase);
+            self.phase = phase;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::consts::*;
+    use heapless::Vec;
+
+    use super::*;
+
+    /// A control packet, including the protocol field.
+    type Pkt = Vec<u8, U128>;
+
+    fn emit(pkt: Packet<'_>) -> Pkt {
+        let mut buf = Pkt::new();
+        unwrap!(buf.resize_default(pkt.buffer_len()));
+        pkt.emit(&mut buf);
+        buf
+    }
+
+    /// Control packet of `proto` with `data` after the header.
+    fn packet(proto: ProtocolType, code: Code, id: u8, data: &[u8]) -> Pkt {
+        let mut pkt = Pkt::new();
+        unwrap!(pkt.extend_from_slice(&u16::from(proto).to_be_bytes()));
+        unwrap!(pkt.extend_from_slice(&[code.into(), id]));
+        unwrap!(pkt.extend_from_slice(&(4 + data.len() as u16).to_be_bytes()));
+        unwrap!(pkt.extend_from_slice(data));
+        pkt
+    }
+
+    /// The reply of the peer acknowledging `req`.
+    fn ack(req: &[u8]) -> Pkt {
+        let mut pkt = unwrap!(Pkt::from_slice(req));
+        pkt[2] = Code::ConfigureAck.into();
+        pkt
+    }
+
+    /// Counter posing as a random source.
+    struct TestRng(u32);
+
+    impl Rng for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9);
+            self.0
+        }
+    }
+
+    /// Drives a `PPP` as the peer would, keeping the packets it sends.
+    struct Link<'a> {
+        ppp: PPP<'a>,
+        now: Instant,
+        sent: Vec<Pkt, U16>,
+    }
+
+    impl<'a> Link<'a> {
+        fn new(config: Config<'a>) -> Self {
+            Self {
+                ppp: PPP::new(config),
+                now: Instant::ZERO,
+                sent: Vec::new(),
+            }
+        }
+
+        fn poll(&mut self) {
+            let sent = &mut self.sent;
+            self.ppp.poll(self.now, |pkt| unwrap!(sent.push(emit(pkt))));
+        }
+
+        fn receive(&mut self, pkt: &[u8]) -> Result<(), MalformedError> {
+            let mut pkt = unwrap!(Pkt::from_slice(pkt));
+            let sent = &mut self.sent;
+            self.ppp
+                .received(self.now, &mut pkt, |pkt| unwrap!(sent.push(emit(pkt))))
+        }
+
+        /// Advance the time to the next timer deadline, and poll.
+        fn expire(&mut self) {
+            self.now = unwrap!(self.ppp.poll_at()).max(self.now);
+            self.poll();
+        }
+
+        /// Take the packets sent so far.
+        fn take(&mut self) -> Vec<Pkt, U16> {
+            core::mem::replace(&mut self.sent, Vec::new())
+        }
+
+        /// Take the packets sent so far, keeping their protocol and code.
+        fn sent(&mut self) -> Vec<(ProtocolType, Code), U16> {
+            let pkts = self.take();
+            pkts.iter()
+                .map(|pkt| {
+                    let proto = u16::from_be_bytes([pkt[0], pkt[1]]).into();
+                    (proto, Code::from(pkt[2]))
+                })
+                .collect()
+        }
+
+        /// Take the one packet sent so far, which must have `proto` and `code`.
+        fn take_one(&mut self, proto: ProtocolType, code: Code) -> Pkt {
+            let mut pkts = self.take();
+            assert_eq!(pkts.len(), 1, "{:?}", &pkts[..]);
+            let pkt = unwrap!(pkts.pop());
+            assert_eq!(u16::from_be_bytes([pkt[0], pkt[1]]), proto.into());
+            assert_eq!(Code::from(pkt[2]), code);
+            pkt
+        }
+
+        fn phase(&self) -> Phase {
+            self.ppp.status().phase
+        }
+
+        /// Open the link and bring LCP up, with the peer requesting `options`.
+        fn open_lcp(&mut self, options: &[u8]) {
+            unwrap!(self.ppp.open());
+            self.poll();
+            let req = self.take_one(ProtocolType::LCP, Code::ConfigureReq);
+            unwrap!(self.receive(&ack(&req)));
+            let req = packet(ProtocolType::LCP, Code::ConfigureReq, 1, options);
+            unwrap!(self.receive(&req));
+            assert_eq!(self.sent()[..], [(ProtocolType::LCP, Code::ConfigureAck)]);
+            assert_eq!(self.ppp.lcp.state(), State::Opened);
+            self.poll();
+        }
+
+        /// Open the link and bring LCP and IPv4CP up, without authentication.
+        fn open_all(&mut self) {
+            self.open_lcp(&[]);
+            let req = self.take_one(ProtocolType::IPv4CP, Code::ConfigureReq);
+            unwrap!(self.receive(&ack(&req)));
+            let options = [3, 6, 10, 0, 0, 1];
+            let req = packet(ProtocolType::IPv4CP, Code::ConfigureReq, 1, &options);
+            unwrap!(self.receive(&req));
+            assert_eq!(self.phase(), Phase::Open);
+        }
+    }
+
+    #[test]
+    fn lcp_restart_timer() {
+        let config = Config::default();
+        let (interval, max_configure) = (config.restart_interval, config.max_configure);
+        let mut link = Link::new(config);
+
+        unwrap!(link.ppp.open());
+        // The Open is processed on the next poll.
+        assert_eq!(link.ppp.poll_at(), Some(Instant::ZERO));
+        link.poll();
+        for _ in 0..max_configure {
+            assert_eq!(link.sent()[..], [(ProtocolType::LCP, Code::ConfigureReq)]);
+            assert_eq!(link.phase(), Phase::Establish);
+            let deadline = link.now + interval;
+            assert_eq!(link.ppp.poll_at(), Some(deadline));
+
+            link.now = Instant::from_millis(deadline.total_millis() - 1);
+            link.poll();
+            assert!(link.sent().is_empty());
+            link.expire();
+        }
+
+        // No reply to Max-Configure Configure-Requests.
+        assert!(link.sent().is_empty());
+        let status = link.ppp.status();
+        assert_eq!(status.phase, Phase::Dead);
+        assert_eq!(status.failure, Some(Failure::LcpTimeout));
+        assert_eq!(link.ppp.poll_at(), None);
+    }
+
+    #[test]
+    fn ipv4cp_restart_timer() {
+        let config = Config::default();
+        let max_configure = config.max_configure;
+        let mut link = Link::new(config);
+        link.open_lcp(&[]);
+        assert_eq!(link.phase(), Phase::Network);
+
+        for _ in 0..max_configure {
+            assert_eq!(
+                link.sent()[..],
+                [(ProtocolType::IPv4CP, Code::ConfigureReq)]
+            );
+            link.expire();
+        }
+
+        // IPv4CP gave up, LCP is terminated.
+        let sent = link.sent();
+        assert_eq!(sent.last(), Some(&(ProtocolType::LCP, Code::TerminateReq)));
+        let status = link.ppp.status();
+        assert_eq!(status.phase, Phase::Terminate);
+        assert_eq!(status.failure, Some(Failure::Ipv4cpTimeout));
+    }
+
+    #[test]
+    fn close_without_reply() {
+        let config = Config::default();
+        let max_terminate = config.max_terminate;
+        let mut link = Link::new(config);
+        link.open_lcp(&[]);
+        link.take();
+
+        unwrap!(link.ppp.close());
+        assert_eq!(link.ppp.poll_at(), Some(Instant::ZERO));
+        link.poll();
+        assert_eq!(link.phase(), Phase::Terminate);
+
+        // IPv4CP is closed first, then LCP, each after Max-Terminate Terminate-Requests.
+        for &proto in [ProtocolType::IPv4CP, ProtocolType::LCP].iter() {
+            for _ in 0..max_terminate {
+                assert_eq!(link.sent()[..], [(proto, Code::TerminateReq)]);
+                assert_eq!(link.phase(), Phase::Terminate);
+                link.expire();
+            }
+        }
+
+        assert!(link.sent().is_empty());
+        let status = link.ppp.status();
+        assert_eq!(status.phase, Phase::Dead);
+        assert_eq!(status.failure, None);
+        assert_eq!(link.ppp.poll_at(), None);
+        assert!(link.ppp.close().is_err());
+    }
+
+    #[test]
+    fn close_acked() {
+        let mut link = Link::new(Config::default());
+        link.open_lcp(&[]);
+        link.take();
+
+        unwrap!(link.ppp.close());
+        link.poll();
+        let req = link.take_one(ProtocolType::IPv4CP, Code::TerminateReq);
+        unwrap!(link.receive(&packet(
+            ProtocolType::IPv4CP,
+            Code::TerminateAck,
+            req[3],
+            &[]
+        )));
+        link.poll();
+        let req = link.take_one(ProtocolType::LCP, Code::TerminateReq);
+        assert_eq!(link.phase(), Phase::Terminate);
+
+        unwrap!(link.receive(&packet(ProtocolType::LCP, Code::TerminateAck, req[3], &[])));
+        assert_eq!(link.phase(), Phase::Dead);
+        assert_eq!(link.ppp.poll_at(), None);
+
+        // The link can be opened again.
+        unwrap!(link.ppp.open());
+        link.poll();
+        link.take_one(ProtocolType::LCP, Code::ConfigureReq);
+        assert_eq!(link.phase(), Phase::Establish);
+    }
+
+    #[test]
+    fn close_while_establishing() {
+        let mut link = Link::new(Config::default());
+        unwrap!(link.ppp.open());
+        link.poll();
+        link.take_one(ProtocolType::LCP, Code::ConfigureReq);
+
+        unwrap!(link.ppp.close());
+        link.poll();
+        link.take_one(ProtocolType::LCP, Code::TerminateReq);
+        assert_eq!(link.phase(), Phase::Terminate);
+        link.expire();
+        link.take_one(ProtocolType::LCP, Code::TerminateReq);
+        link.expire();
+        assert_eq!(link.phase(), Phase::Dead);
+        assert_eq!(link.ppp.status().failure, None);
+    }
+
+    #[test]
+    fn lower_layer_down_and_up() {
+        let phases = [
+            Phase::Establish,
+            Phase::Auth,
+            Phase::Network,
+            Phase::Open,
+            Phase::Terminate,
+        ];
+        for &phase in phases.iter() {
+            let mut link = Link::new(Config {
+                username: b"user",
+                password: b"pass",
+                ..Default::default()
+            });
+            match phase {
+                Phase::Establish => {
+                    unwrap!(link.ppp.open());
+                    link.poll();
+                }
+                // The peer asks for PAP.
+                Phase::Auth => link.open_lcp(&[3, 4, 0xc0, 0x23]),
+                Phase::Network => link.open_lcp(&[]),
+                Phase::Open => link.open_all(),
+                _ => {
+                    link.open_lcp(&[]);
+                    unwrap!(link.ppp.close());
+                    link.poll();
                 }
             }
-            Phase::Open => {}
+            assert_eq!(link.phase(), phase);
+            link.take();
+
+            link.ppp.lower_down();
+            link.poll();
+            assert_eq!(link.sent().len(), 0, "{:?}", phase);
+            let status = link.ppp.status();
+            assert_eq!(status.phase, Phase::Dead, "{:?}", phase);
+            assert_eq!(status.failure, None, "{:?}", phase);
+            assert_eq!(link.ppp.poll_at(), None, "{:?}", phase);
+
+            link.ppp.lower_up();
+            link.poll();
+            if phase == Phase::Terminate {
+                // The link was being closed, it stays down.
+                assert!(link.sent().is_empty());
+                assert_eq!(link.phase(), Phase::Dead);
+            } else {
+                // The link is still open, it is established again.
+                link.take_one(ProtocolType::LCP, Code::ConfigureReq);
+                assert_eq!(link.phase(), Phase::Establish, "{:?}", phase);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let mut link = Link::new(Config::default());
+        unwrap!(link.ppp.open());
+        link.poll();
+        link.take();
+
+        let cases: [(&[u8], MalformedError); 6] = [
+            // Shorter than the protocol field.
+            (&[0xc0], MalformedError::TooShort),
+            // Truncated header.
+            (&[0xc0, 0x21, 1, 1, 0], MalformedError::TooShort),
+            // Length longer than the packet.
+            (&[0xc0, 0x21, 1, 1, 0, 10, 1, 4], MalformedError::TooShort),
+            // Option longer than the packet.
+            (&[0xc0, 0x21, 1, 1, 0, 6, 1, 4], MalformedError::BadOption),
+            // Option Length shorter than the option header.
+            (&[0xc0, 0x21, 1, 1, 0, 6, 1, 1], MalformedError::BadOption),
+            // Truncated option header.
+            (&[0xc0, 0x21, 1, 1, 0, 5, 1], MalformedError::BadOption),
+        ];
+        for (i, &(pkt, err)) in cases.iter().enumerate() {
+            assert_eq!(link.receive(pkt), Err(err), "{:?}", pkt);
+            assert_eq!(link.sent().len(), 0, "{:?}", pkt);
+            let status = link.ppp.status();
+            assert_eq!(status.counters.malformed_packets, i as u32 + 1);
+            assert_eq!(status.last_malformed, Some(err));
+            assert_eq!(link.ppp.lcp.state(), State::ReqSent);
+        }
+
+        // Padding after the Length is ignored.
+        unwrap!(link.receive(&[0xc0, 0x21, 1, 1, 0, 4, 0, 0]));
+        assert_eq!(link.sent()[..], [(ProtocolType::LCP, Code::ConfigureAck)]);
+        assert_eq!(link.ppp.status().counters.malformed_packets, 6);
+    }
+
+    #[test]
+    fn looped_back() {
+        let mut rng = TestRng(0);
+        let mut link = Link::new(Config {
+            rng: Some(&mut rng),
+            ..Config::default()
+        });
+        unwrap!(link.ppp.open());
+        link.poll();
+
+        // Everything we send comes back.
+        let mut rounds = 0;
+        while link.ppp.status().failure.is_none() {
+            rounds += 1;
+            assert!(rounds <= 10);
+            for pkt in link.take() {
+                unwrap!(link.receive(&pkt));
+            }
         }
+        assert_eq!(link.ppp.status().failure, Some(Failure::LoopedBack));
+        assert!(link.ppp.lcp.proto().looped_back());
+        let sent = link.sent();
+        assert_eq!(sent.last(), Some(&(ProtocolType::LCP, Code::TerminateReq)));
+    }
+
+    #[test]
+    fn echo_failure_with_peer_requests() {
+        let mut link = Link::new(Config {
+            echo_interval: Some(Duration::from_secs(10)),
+            echo_failure: 3,
+            ..Config::default()
+        });
+        link.open_all();
+        link.take();
 
-        if old_phase != self.phase {
-            info!("PPP link phase {:?} -> {:?}", old_phase, self.phase);
+        // The peer keeps sending its own Echo-Requests but never replies to ours.
+        for id in 1..=3 {
+            link.expire();
+            link.take_one(ProtocolType::LCP, Code::EchoReq);
+            let req = packet(ProtocolType::LCP, Code::EchoReq, id, &[0; 4]);
+            unwrap!(link.receive(&req));
+            link.take_one(ProtocolType::LCP, Code::EchoReply);
+            assert_eq!(link.ppp.status().failure, None);
         }
+        link.expire();
+        assert_eq!(link.ppp.status().failure, Some(Failure::EchoTimeout));
     }
 }
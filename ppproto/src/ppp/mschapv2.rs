@@ -0,0 +1,254 @@
+//! MS-CHAPv2 computations, RFC 2759, and the MPPE master key, RFC 3079.
+
+use core::convert::TryInto;
+
+use crate::crypto::{des_encrypt, Md4, Sha1};
+
+const MAGIC1: &[u8] = b"Magic server to client signing constant";
+const MAGIC2: &[u8] = b"Pad to make it do more than one iteration";
+const MASTER_KEY_MAGIC: &[u8] = b"This is the MPPE Master Key";
+
+/// Failure reported by the peer in an MS-CHAPv2 Failure packet, RFC 2759 section 6.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MsChapFailure {
+    /// Windows error code, such as 691 for a wrong user name or password, 648 for an
+    /// expired password or 649 for an account without dial-in permission.
+    pub error: u32,
+    /// The peer allows retrying with other credentials.
+    pub retry: bool,
+    /// Authenticator challenge to retry with, if any.
+    pub challenge: Option<[u8; 16]>,
+}
+
+impl MsChapFailure {
+    /// Parse the message of a Failure packet, such as `E=691 R=1 C=<32 hex digits> V=3 M=...`.
+    pub(crate) fn parse(msg: &[u8]) -> Self {
+        let mut failure = Self {
+            error: 0,
+            retry: false,
+            challenge: None,
+        };
+        for field in msg.split(|&b| b == b' ') {
+            match field {
+                // The message is last, and may contain spaces.
+                [b'M', b'=', ..] => break,
+                [b'E', b'=', value @ ..] => failure.error = parse_decimal(value).unwrap_or(0),
+                [b'R', b'=', value @ ..] => failure.retry = value == b"1",
+                [b'C', b'=', value @ ..] => {
+                    let mut challenge = [0; 16];
+                    if parse_hex(value, &mut challenge) {
+                        failure.challenge = Some(challenge);
+                    }
+                }
+                _ => {}
+            }
+        }
+        failure
+    }
+}
+
+fn parse_decimal(s: &[u8]) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0u32, |n, &c| match c {
+        b'0'..=b'9' => n.checked_mul(10)?.checked_add((c - b'0') as u32),
+        _ => None,
+    })
+}
+
+/// Parse exactly `out.len()` bytes of hex digits, in either case.
+fn parse_hex(s: &[u8], out: &mut [u8]) -> bool {
+    if s.len() != out.len() * 2 {
+        return false;
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    };
+    for (b, pair) in out.iter_mut().zip(s.chunks(2)) {
+        match (digit(pair[0]), digit(pair[1])) {
+            (Some(hi), Some(lo)) => *b = hi << 4 | lo,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// NtPasswordHash: MD4 of the password in UTF-16LE.
+///
+/// The password is UTF-8. If it isn't valid UTF-8, each byte is taken as a character.
+fn nt_password_hash(password: &[u8]) -> [u8; 16] {
+    let mut md4 = Md4::new();
+    let mut unit = |c: u16| md4.update(&c.to_le_bytes());
+    match core::str::from_utf8(password) {
+        Ok(s) => s.encode_utf16().for_each(&mut unit),
+        Err(_) => password.iter().for_each(|&b| unit(b as u16)),
+    }
+    md4.finalize()
+}
+
+fn hash_nt_password_hash(password: &[u8]) -> [u8; 16] {
+    let mut md4 = Md4::new();
+    md4.update(&nt_password_hash(password));
+    md4.finalize()
+}
+
+/// ChallengeHash: SHA-1 of both challenges and the user name, without the Windows domain.
+fn challenge_hash(
+    peer_challenge: &[u8; 16],
+    auth_challenge: &[u8; 16],
+    username: &[u8],
+) -> [u8; 8] {
+    let username = match username.iter().rposition(|&b| b == b'\\') {
+        Some(i) => &username[i + 1..],
+        None => username,
+    };
+    let mut sha1 = Sha1::new();
+    sha1.update(peer_challenge);
+    sha1.update(auth_challenge);
+    sha1.update(username);
+    let mut out = [0; 8];
+    out.copy_from_slice(&sha1.finalize()[..8]);
+    out
+}
+
+/// GenerateNTResponse, RFC 2759 section 8.1.
+pub(crate) fn nt_response(
+    auth_challenge: &[u8; 16],
+    peer_challenge: &[u8; 16],
+    username: &[u8],
+    password: &[u8],
+) -> [u8; 24] {
+    let challenge = challenge_hash(peer_challenge, auth_challenge, username);
+    let mut key = [0; 21];
+    key[..16].copy_from_slice(&nt_password_hash(password));
+
+    let mut out = [0; 24];
+    for (out, key) in out.chunks_mut(8).zip(key.chunks(7)) {
+        let key: &[u8; 7] = key.try_into().unwrap();
+        out.copy_from_slice(&des_encrypt(key, &challenge));
+    }
+    out
+}
+
+/// GenerateAuthenticatorResponse, RFC 2759 section 8.7. Returns the 20 bytes that the
+/// Success message carries in hex after `S=`.
+pub(crate) fn authenticator_response(
+    auth_challenge: &[u8; 16],
+    peer_challenge: &[u8; 16],
+    nt_response: &[u8; 24],
+    username: &[u8],
+    password: &[u8],
+) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(&hash_nt_password_hash(password));
+    sha1.update(nt_response);
+    sha1.update(MAGIC1);
+    let digest = sha1.finalize();
+
+    let mut sha1 = Sha1::new();
+    sha1.update(&digest);
+    sha1.update(&challenge_hash(peer_challenge, auth_challenge, username));
+    sha1.update(MAGIC2);
+    sha1.finalize()
+}
+
+/// Returns true if the message of a Success packet starts with the expected
+/// authenticator response, `S=` and 40 hex digits.
+pub(crate) fn check_success(msg: &[u8], expected: &[u8; 20]) -> bool {
+    let mut response = [0; 20];
+    msg.len() >= 42
+        && msg.starts_with(b"S=")
+        && parse_hex(&msg[2..42], &mut response)
+        && response == *expected
+}
+
+/// GetMasterKey, RFC 3079 section 3.4.
+pub(crate) fn master_key(nt_response: &[u8; 24], password: &[u8]) -> [u8; 16] {
+    let mut sha1 = Sha1::new();
+    sha1.update(&hash_nt_password_hash(password));
+    sha1.update(nt_response);
+    sha1.update(MASTER_KEY_MAGIC);
+    let mut out = [0; 16];
+    out.copy_from_slice(&sha1.finalize()[..16]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 2759 section 9.2, also used by RFC 3079 section 3.5.3.
+    const USERNAME: &[u8] = b"User";
+    const PASSWORD: &[u8] = b"clientPass";
+    const AUTH_CHALLENGE: [u8; 16] = [
+        0x5b, 0x5d, 0x7c, 0x7d, 0x7b, 0x3f, 0x2f, 0x3e, 0x3c, 0x2c, 0x60, 0x21, 0x32, 0x26, 0x26,
+        0x28,
+    ];
+    const PEER_CHALLENGE: [u8; 16] = [
+        0x21, 0x40, 0x23, 0x24, 0x25, 0x5e, 0x26, 0x2a, 0x28, 0x29, 0x5f, 0x2b, 0x3a, 0x33, 0x7c,
+        0x7e,
+    ];
+    const NT_RESPONSE: [u8; 24] = [
+        0x82, 0x30, 0x9e, 0xcd, 0x8d, 0x70, 0x8b, 0x5e, 0xa0, 0x8f, 0xaa, 0x39, 0x81, 0xcd, 0x83,
+        0x54, 0x42, 0x33, 0x11, 0x4a, 0x3d, 0x85, 0xd6, 0xdf,
+    ];
+
+    #[test]
+    fn rfc2759_example() {
+        assert_eq!(
+            challenge_hash(&PEER_CHALLENGE, &AUTH_CHALLENGE, USERNAME),
+            [0xd0, 0x2e, 0x43, 0x86, 0xbc, 0xe9, 0x12, 0x26]
+        );
+        assert_eq!(
+            nt_password_hash(PASSWORD),
+            [
+                0x44, 0xeb, 0xba, 0x8d, 0x53, 0x12, 0xb8, 0xd6, 0x11, 0x47, 0x44, 0x11, 0xf5, 0x69,
+                0x89, 0xae
+            ]
+        );
+        assert_eq!(
+            nt_response(&AUTH_CHALLENGE, &PEER_CHALLENGE, USERNAME, PASSWORD),
+            NT_RESPONSE
+        );
+
+        let response = authenticator_response(
+            &AUTH_CHALLENGE,
+            &PEER_CHALLENGE,
+            &NT_RESPONSE,
+            USERNAME,
+            PASSWORD,
+        );
+        assert!(check_success(
+            b"S=407A5589115FD0D6209F510FE9C04566932CDA56",
+            &response
+        ));
+        // Only the Windows domain is stripped from the user name.
+        assert_eq!(
+            nt_response(&AUTH_CHALLENGE, &PEER_CHALLENGE, b"DOMAIN\\User", PASSWORD),
+            NT_RESPONSE
+        );
+    }
+
+    #[test]
+    fn rfc3079_master_key() {
+        assert_eq!(
+            hash_nt_password_hash(PASSWORD),
+            [
+                0x41, 0xc0, 0x0c, 0x58, 0x4b, 0xd2, 0xd9, 0x1c, 0x40, 0x17, 0xa2, 0xa1, 0x2f, 0xa5,
+                0x9f, 0x3f
+            ]
+        );
+        assert_eq!(
+            master_key(&NT_RESPONSE, PASSWORD),
+            [
+                0xfd, 0xec, 0xe3, 0x71, 0x7a, 0x8c, 0x83, 0x8c, 0xb3, 0x88, 0xe5, 0x27, 0xae, 0x3c,
+                0xdd, 0x31
+            ]
+        );
+    }
+}
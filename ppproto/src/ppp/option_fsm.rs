@@ -1,8 +1,13 @@
-use crate::fmt::{assert, panic, unreachable, *};
-use core::convert::TryInto;
+use crate::fmt::{panic, unreachable, *};
 use heapless::Vec;
 
-use crate::wire::{Code, OptionVal, Options, PPPPayload, Packet, Payload, ProtocolType};
+use super::timer::Timer;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{
+    parse_header, Code, MalformedError, MaxOptions, MaxOptionsBufLen, Options, PPPPayload, Packet,
+    Payload, ProtocolType, MAX_CONTROL_PACKET_LEN,
+};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -20,30 +25,102 @@ pub(crate) trait Protocol {
 
     fn peer_options_start(&mut self);
     fn peer_option_received(&mut self, code: u8, data: &[u8]) -> Verdict;
+
+    /// Returns true if our options changed while handling the peer's options, such as
+    /// a new magic number. Clears the flag.
+    fn take_own_options_changed(&mut self) -> bool {
+        false
+    }
+
+    /// Our negotiated magic number, for Echo-Replies. Only LCP has one.
+    fn magic_number(&self) -> u32 {
+        0
+    }
 }
 
+/// States of the option negotiation automaton, as described in RFC 1661 section 4.2
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub(crate) enum State {
+    Initial,
+    Starting,
     Closed,
+    Stopped,
+    Closing,
+    Stopping,
     ReqSent,
     AckReceived,
     AckSent,
     Opened,
 }
 
+/// Notifications from the automaton to the layer above (tlu, tld, tls, tlf).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum LayerEvent {
+    /// This-Layer-Up: the automaton entered the Opened state.
+    Up,
+    /// This-Layer-Down: the automaton left the Opened state.
+    Down,
+    /// This-Layer-Started: the automaton needs the lower layer.
+    Started,
+    /// This-Layer-Finished: the automaton no longer needs the lower layer.
+    Finished,
+}
+
+/// Configure-Naks and Configure-Rejects sent for one peer option since the last Configure-Ack.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct OptionFailures {
+    code: u8,
+    naks: u8,
+    rejs: u8,
+}
+
 pub(crate) struct OptionFsm<P> {
     id: u8,
     state: State,
     proto: P,
+
+    timer: Timer,
+    restart_count: u8,
+    failures: Vec<OptionFailures, MaxOptions>,
+    rejected: bool,
+    not_converging: bool,
+
+    /// Identifier of the outstanding Configure-Request. None once a reply to it was accepted.
+    req_id: Option<u8>,
+    /// Options of the last Configure-Request sent, as they appear on the wire.
+    req_options: Vec<u8, MaxOptionsBufLen>,
+    /// Configure-Ack/Nak/Rej discarded because they didn't match the outstanding request.
+    discarded: u32,
+
+    restart_interval: Duration,
+    max_configure: u8,
+    max_terminate: u8,
+    max_failure: u8,
 }
 
 impl<P: Protocol> OptionFsm<P> {
-    pub fn new(proto: P) -> Self {
+    pub fn new(proto: P, config: &Config<'_>) -> Self {
         Self {
             id: 1,
-            state: State::Closed,
+            state: State::Initial,
             proto,
+
+            timer: Timer::Stopped,
+            restart_count: 0,
+            failures: Vec::new(),
+            rejected: false,
+            not_converging: false,
+
+            req_id: None,
+            req_options: Vec::new(),
+            discarded: 0,
+
+            restart_interval: config.restart_interval,
+            max_configure: config.max_configure,
+            max_terminate: config.max_terminate,
+            max_failure: config.max_failure,
         }
     }
 
@@ -59,120 +136,606 @@ impl<P: Protocol> OptionFsm<P> {
         &mut self.proto
     }
 
-    pub fn open(&mut self) -> Packet<'_> {
-        assert!(self.state == State::Closed);
-        self.state = State::ReqSent;
-        self.send_configure_request()
+    /// Lower layer is Up.
+    pub fn up(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.state {
+            State::Initial => self.set_state(State::Closed),
+            State::Starting => {
+                self.start_configure(tx);
+                self.set_state(State::ReqSent);
+            }
+            x => info!("{:?}: ignoring Up in state {:?}", self.proto.protocol(), x),
+        }
+        None
     }
 
-    pub fn close(&mut self) {
-        self.state = State::Closed;
+    /// Lower layer is Down.
+    pub fn down(&mut self) -> Option<LayerEvent> {
+        match self.state {
+            State::Closed | State::Closing => {
+                self.set_state(State::Initial);
+                None
+            }
+            State::Stopped => {
+                self.set_state(State::Starting);
+                Some(LayerEvent::Started)
+            }
+            State::Stopping | State::ReqSent | State::AckReceived | State::AckSent => {
+                self.set_state(State::Starting);
+                None
+            }
+            State::Opened => {
+                self.set_state(State::Starting);
+                Some(LayerEvent::Down)
+            }
+            x => {
+                info!(
+                    "{:?}: ignoring Down in state {:?}",
+                    self.proto.protocol(),
+                    x
+                );
+                None
+            }
+        }
     }
 
-    pub fn handle(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
-        if pkt.len() < 6 {
-            info!("warn: too short");
-            return;
+    /// Administrative Open.
+    pub fn open(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.state {
+            State::Initial => {
+                self.set_state(State::Starting);
+                Some(LayerEvent::Started)
+            }
+            State::Closed => {
+                self.start_configure(tx);
+                self.set_state(State::ReqSent);
+                None
+            }
+            // Restart option: the link was stopped by the peer or by a failure, so
+            // do a Down + Up to actively negotiate again instead of waiting for the peer.
+            State::Stopped => {
+                self.set_state(State::Starting);
+                self.up(tx);
+                Some(LayerEvent::Started)
+            }
+            State::Closing => {
+                self.set_state(State::Stopping);
+                None
+            }
+            _ => None,
         }
-        let code = Code::from(pkt[2]);
-        let id = pkt[3];
-        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
-        if len + 2 > pkt.len() {
-            info!("warn: len too short");
-            return;
+    }
+
+    /// Administrative Close.
+    pub fn close(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.state {
+            State::Starting => {
+                self.set_state(State::Initial);
+                Some(LayerEvent::Finished)
+            }
+            State::Stopped => {
+                self.set_state(State::Closed);
+                None
+            }
+            State::Stopping => {
+                self.set_state(State::Closing);
+                None
+            }
+            State::ReqSent | State::AckReceived | State::AckSent => {
+                self.irc_terminate();
+                self.str(tx);
+                self.set_state(State::Closing);
+                None
+            }
+            State::Opened => {
+                self.irc_terminate();
+                self.str(tx);
+                self.set_state(State::Closing);
+                Some(LayerEvent::Down)
+            }
+            _ => None,
         }
-        let pkt = &mut pkt[..len + 2];
+    }
 
-        info!("{:?}: rx {:?}", self.proto.protocol(), code);
-        let old_state = self.state;
-        match (code, self.state) {
-            // reply EchoReq on state Opened, ignore in all other states (including Closed!)
-            (Code::EchoReq, State::Opened) => tx(self.send_echo_response(pkt)),
-            (Code::EchoReq, x) => {
-                info!("ignoring unexpected EchoReq in state {:?}", x)
-            }
-
-            // DiscardReqs are, well, discarded.
-            (Code::DiscardReq, _) => {}
-
-            // in state Closed, reply to any packet with TerminateAck (except to EchoReq!)
-            (_, State::Closed) => tx(self.send_terminate_ack(id)),
-
-            (Code::ConfigureReq, _) => {
-                let resp = self.received_configure_req(pkt);
-                let acked = matches!(resp.payload, Payload::PPP(Code::ConfigureAck, _, _));
-                tx(resp);
-
-                match (acked, self.state) {
-                    (_, State::Closed) => unreachable!(),
-                    (true, State::ReqSent) => self.state = State::AckSent,
-                    (true, State::AckReceived) => self.state = State::Opened,
-                    (true, State::AckSent) => self.state = State::AckSent,
-                    (true, State::Opened) => {
-                        tx(self.send_configure_request());
-                        self.state = State::AckSent;
-                    }
-                    (false, State::AckSent) => self.state = State::ReqSent,
-                    (false, State::Opened) => {
-                        tx(self.send_configure_request());
-                        self.state = State::ReqSent;
-                    }
-                    (false, _) => {}
+    /// Drive the restart timer. Must be called after every other event.
+    pub fn poll(&mut self, now: Instant, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        if self.timer.poll(now, self.restart_interval) {
+            self.timeout(tx)
+        } else {
+            None
+        }
+    }
+
+    /// When the restart timer expires, while a Configure- or Terminate-Request is unanswered.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer.poll_at()
+    }
+
+    /// Returns true if the peer rejected this protocol, or one of the codes needed to negotiate it.
+    pub fn rejected(&self) -> bool {
+        self.rejected
+    }
+
+    /// Returns true if the peer kept requesting options after we rejected them Max-Failure times.
+    ///
+    /// The automaton stops answering the peer's Configure-Requests, the layer above should close it.
+    pub fn not_converging(&self) -> bool {
+        self.not_converging
+    }
+
+    /// Returns the number of Configure-Ack/Nak/Rej discarded because they didn't
+    /// match the outstanding Configure-Request.
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// The peer sent a Protocol-Reject for this protocol.
+    pub fn protocol_rejected(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        self.rxj(false, tx)
+    }
+
+    /// Returns true if Configure-Requests are being exchanged.
+    pub fn is_negotiating(&self) -> bool {
+        matches!(
+            self.state,
+            State::ReqSent | State::AckReceived | State::AckSent
+        )
+    }
+
+    /// Restart timer expired.
+    fn timeout(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        if self.restart_count > 0 {
+            // TO+
+            match self.state {
+                State::Closing | State::Stopping => self.str(tx),
+                State::ReqSent | State::AckSent => self.scr(tx),
+                State::AckReceived => {
+                    self.scr(tx);
+                    self.set_state(State::ReqSent);
                 }
+                _ => {}
             }
+            None
+        } else {
+            // TO-
+            match self.state {
+                State::Closing => {
+                    self.set_state(State::Closed);
+                    Some(LayerEvent::Finished)
+                }
+                State::Stopping | State::ReqSent | State::AckReceived | State::AckSent => {
+                    self.set_state(State::Stopped);
+                    Some(LayerEvent::Finished)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// Handle a received packet, including the protocol field.
+    ///
+    /// Malformed packets are dropped without affecting the automaton, and the error is returned.
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<Option<LayerEvent>, MalformedError> {
+        let (code, id, data) = parse_header(pkt).ok_or(MalformedError::TooShort)?;
+        let len = 6 + data.len();
+
+        info!("{:?}: rx {:?}", self.proto.protocol(), code);
+
+        // No packets are expected before the lower layer is up.
+        if matches!(self.state, State::Initial | State::Starting) {
+            info!(
+                "{:?}: ignoring {:?} in state {:?}",
+                self.proto.protocol(),
+                code,
+                self.state
+            );
+            return Ok(None);
+        }
+
+        if matches!(
+            code,
+            Code::ConfigureAck | Code::ConfigureNack | Code::ConfigureRej
+        ) && !self.reply_matches(code, id, data)?
+        {
+            info!(
+                "{:?}: discarding {:?} id {:?} not matching the outstanding request",
+                self.proto.protocol(),
+                code,
+                id
+            );
+            self.discarded = self.discarded.wrapping_add(1);
+            return Ok(None);
+        }
 
-            (Code::ConfigureAck, State::ReqSent) => self.state = State::AckReceived,
-            (Code::ConfigureAck, State::AckSent) => self.state = State::Opened,
-            (Code::ConfigureAck, State::AckReceived) | (Code::ConfigureAck, State::Opened) => {
-                self.state = State::ReqSent;
-                tx(self.send_configure_request())
+        let event = match code {
+            Code::ConfigureReq => self.rcr(id, data, &mut tx)?,
+            Code::ConfigureAck => self.rca(id, &mut tx),
+            Code::ConfigureNack | Code::ConfigureRej => self.rcn(code, id, data, &mut tx)?,
+            Code::TerminateReq => self.rtr(id, &mut tx),
+            Code::TerminateAck => self.rta(&mut tx),
+            Code::EchoReq | Code::EchoReply | Code::DiscardReq
+                if self.proto.protocol() == ProtocolType::LCP =>
+            {
+                self.rxr(code, &mut pkt[..len], &mut tx);
+                None
             }
+            // Handled by PPP, they don't affect the state.
+            Code::Identification | Code::TimeRemaining
+                if self.proto.protocol() == ProtocolType::LCP =>
+            {
+                None
+            }
+            Code::CodeRej => {
+                // The peer can't do without the codes needed to negotiate and terminate.
+                let rejected = Code::from(*data.first().ok_or(MalformedError::TooShort)?);
+                let permitted = !matches!(
+                    rejected,
+                    Code::ConfigureReq
+                        | Code::ConfigureAck
+                        | Code::ConfigureNack
+                        | Code::ConfigureRej
+                        | Code::TerminateReq
+                        | Code::TerminateAck
+                        | Code::CodeRej
+                );
+                info!(
+                    "{:?}: peer rejected code {:?}",
+                    self.proto.protocol(),
+                    rejected
+                );
+                self.rxj(permitted, &mut tx)
+            }
+            // The rejected protocol is handled by the layer above, LCP itself goes on.
+            Code::ProtocolRej if self.proto.protocol() == ProtocolType::LCP => {
+                self.rxj(true, &mut tx)
+            }
+            // Receive-Unknown-Code
+            _ => {
+                info!(
+                    "{:?}: rejecting unknown code {:?}",
+                    self.proto.protocol(),
+                    pkt[2]
+                );
+                tx(self.send_code_reject(&mut pkt[..len]));
+                None
+            }
+        };
+        Ok(event)
+    }
+
+    /// Check a Configure-Ack/Nak/Rej against the outstanding Configure-Request.
+    ///
+    /// The Identifier must match, an Ack must carry exactly the options we sent, and a
+    /// Reject must only carry options we sent, unmodified. Replies don't matter in the
+    /// states where no request is outstanding, so they're always accepted there.
+    ///
+    /// Only the first reply to a request is accepted, later ones are stale duplicates.
+    /// In Ack-Rcvd and Opened our request was acked already, so replies are always
+    /// discarded there and the RCA and RCN events of RFC 1661 don't happen, as in pppd.
+    /// The peer renegotiates an open link with a Configure-Request instead.
+    fn reply_matches(
+        &mut self,
+        code: Code,
+        id: u8,
+        options: &[u8],
+    ) -> Result<bool, MalformedError> {
+        if !matches!(
+            self.state,
+            State::ReqSent | State::AckReceived | State::AckSent | State::Opened
+        ) {
+            return Ok(true);
+        }
 
-            (Code::ConfigureNack, _) | (Code::ConfigureRej, _) => {
-                let is_rej = code == Code::ConfigureRej;
+        if self.req_id != Some(id) {
+            return Ok(false);
+        }
 
-                if pkt.len() < 6 {
-                    panic!("too short")
-                }
-                let pkt = &pkt[6..]; // skip header
+        let ok = match code {
+            Code::ConfigureAck => options == &self.req_options[..],
+            Code::ConfigureRej => {
+                let req_options = &self.req_options[..];
+                let mut ok = true;
+                parse_options(options, |code, data| {
+                    let mut found = false;
+                    // Our own options, always well formed.
+                    let _ = parse_options(req_options, |req_code, req_data| {
+                        found |= req_code == code && req_data == data;
+                    });
+                    ok &= found;
+                })?;
+                ok
+            }
+            _ => {
+                parse_options(options, |_, _| {})?;
+                true
+            }
+        };
+
+        if ok {
+            self.req_id = None;
+        }
+        Ok(ok)
+    }
+
+    /// Receive-Configure-Request
+    fn rcr(
+        &mut self,
+        id: u8,
+        options: &[u8],
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<Option<LayerEvent>, MalformedError> {
+        match self.state {
+            State::Closed => {
+                tx(self.send_terminate_ack(id));
+                return Ok(None);
+            }
+            State::Closing | State::Stopping => return Ok(None),
+            _ => {}
+        }
 
-                parse_options(pkt, |code, data| {
-                    self.proto.own_option_nacked(code, data, is_rej)
-                })
-                .unwrap();
+        let resp = match self.received_configure_req(id, options)? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        let good = matches!(resp.payload, Payload::PPP(Code::ConfigureAck, _, _));
+        let next = if good { State::AckSent } else { State::ReqSent };
 
-                match self.state {
-                    State::Closed => unreachable!(),
-                    State::AckSent => {}
-                    _ => self.state = State::ReqSent,
+        // The Configure-Request in flight, or already acked, carries our old options.
+        // Send a new one, with a new Identifier so a late Ack of the old one is discarded.
+        if self.proto.take_own_options_changed()
+            && matches!(
+                self.state,
+                State::ReqSent | State::AckReceived | State::AckSent
+            )
+        {
+            self.scr(&mut tx);
+            if self.state == State::AckReceived {
+                self.set_state(State::ReqSent);
+            }
+        }
+
+        let mut event = None;
+        match self.state {
+            State::Stopped => {
+                self.irc_configure();
+                self.scr(&mut tx);
+                self.set_state(next);
+            }
+            State::ReqSent | State::AckSent => self.set_state(next),
+            State::AckReceived => {
+                if good {
+                    self.set_state(State::Opened);
+                    event = Some(LayerEvent::Up);
                 }
-                tx(self.send_configure_request())
             }
-            (Code::TerminateReq, State::Opened) => {
-                self.state = State::Closed;
-                tx(self.send_terminate_ack(id))
+            State::Opened => {
+                self.scr(&mut tx);
+                self.set_state(next);
+                event = Some(LayerEvent::Down);
+            }
+            _ => unreachable!(),
+        }
+        tx(resp);
+        Ok(event)
+    }
+
+    /// Receive-Configure-Ack
+    fn rca(&mut self, id: u8, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.state {
+            State::Closed | State::Stopped => tx(self.send_terminate_ack(id)),
+            State::ReqSent => {
+                self.irc_configure();
+                self.set_state(State::AckReceived);
+            }
+            State::AckReceived => {
+                // Crossed connection
+                self.scr(tx);
+                self.set_state(State::ReqSent);
             }
-            (Code::TerminateReq, State::ReqSent)
-            | (Code::TerminateReq, State::AckReceived)
-            | (Code::TerminateReq, State::AckSent) => {
-                self.state = State::ReqSent;
-                tx(self.send_terminate_ack(id))
+            State::AckSent => {
+                self.irc_configure();
+                self.set_state(State::Opened);
+                return Some(LayerEvent::Up);
             }
+            State::Opened => {
+                self.scr(tx);
+                self.set_state(State::ReqSent);
+                return Some(LayerEvent::Down);
+            }
+            _ => {}
+        }
+        None
+    }
 
-            x => info!(
-                "ignoring unexpected packet {:?} in state {:?}",
-                x, self.state
-            ),
-        };
+    /// Receive-Configure-Nak/Rej
+    fn rcn(
+        &mut self,
+        code: Code,
+        id: u8,
+        options: &[u8],
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<Option<LayerEvent>, MalformedError> {
+        match self.state {
+            State::Closed | State::Stopped => {
+                tx(self.send_terminate_ack(id));
+                return Ok(None);
+            }
+            State::Closing | State::Stopping => return Ok(None),
+            _ => {}
+        }
+
+        let is_rej = code == Code::ConfigureRej;
+
+        // Check the whole packet before applying any of the options.
+        parse_options(options, |_, _| {})?;
+        parse_options(options, |code, data| {
+            self.proto.own_option_nacked(code, data, is_rej)
+        })?;
+
+        match self.state {
+            State::ReqSent => {
+                self.irc_configure();
+                self.scr(tx);
+            }
+            State::AckReceived => {
+                self.scr(tx);
+                self.set_state(State::ReqSent);
+            }
+            State::AckSent => {
+                self.irc_configure();
+                self.scr(tx);
+            }
+            State::Opened => {
+                self.scr(tx);
+                self.set_state(State::ReqSent);
+                return Ok(Some(LayerEvent::Down));
+            }
+            _ => unreachable!(),
+        }
+        Ok(None)
+    }
+
+    /// Receive-Terminate-Request
+    fn rtr(&mut self, id: u8, mut tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        tx(self.send_terminate_ack(id));
+        match self.state {
+            State::AckReceived | State::AckSent => self.set_state(State::ReqSent),
+            State::Opened => {
+                // zrc: the restart timer still runs once, to give the peer time
+                // to receive our TerminateAck before we drop to Stopped.
+                self.restart_count = 0;
+                self.timer.start();
+                self.set_state(State::Stopping);
+                return Some(LayerEvent::Down);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Receive-Terminate-Ack
+    fn rta(&mut self, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        match self.state {
+            State::Closing => {
+                self.set_state(State::Closed);
+                Some(LayerEvent::Finished)
+            }
+            State::Stopping => {
+                self.set_state(State::Stopped);
+                Some(LayerEvent::Finished)
+            }
+            State::AckReceived => {
+                self.set_state(State::ReqSent);
+                None
+            }
+            State::Opened => {
+                self.scr(tx);
+                self.set_state(State::ReqSent);
+                Some(LayerEvent::Down)
+            }
+            _ => None,
+        }
+    }
+
+    /// Receive-Code-Reject, Receive-Protocol-Reject
+    fn rxj(&mut self, permitted: bool, tx: impl FnMut(Packet<'_>)) -> Option<LayerEvent> {
+        if permitted {
+            // RXJ+
+            if self.state == State::AckReceived {
+                self.set_state(State::ReqSent);
+            }
+            return None;
+        }
+
+        // RXJ-
+        self.rejected = true;
+        match self.state {
+            State::Closed | State::Closing => {
+                self.set_state(State::Closed);
+                Some(LayerEvent::Finished)
+            }
+            State::Stopped
+            | State::Stopping
+            | State::ReqSent
+            | State::AckReceived
+            | State::AckSent => {
+                self.set_state(State::Stopped);
+                Some(LayerEvent::Finished)
+            }
+            State::Opened => {
+                self.irc_terminate();
+                self.str(tx);
+                self.set_state(State::Stopping);
+                Some(LayerEvent::Down)
+            }
+            _ => None,
+        }
+    }
 
-        if old_state != self.state {
+    /// Receive-Echo-Request, Receive-Echo-Reply, Receive-Discard-Request
+    fn rxr(&mut self, code: Code, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
+        // reply EchoReq on state Opened, ignore in all other states (including Closed!)
+        if code == Code::EchoReq && self.state == State::Opened {
+            tx(self.send_echo_response(pkt))
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        if self.state != state {
             info!(
                 "{:?}: state {:?} -> {:?}",
                 self.proto.protocol(),
-                old_state,
-                self.state
+                self.state,
+                state
             );
+            self.state = state;
         }
+
+        // The restart timer only runs in these states.
+        if !matches!(
+            state,
+            State::Closing | State::Stopping | State::ReqSent | State::AckReceived | State::AckSent
+        ) {
+            self.timer.stop();
+        }
+    }
+
+    /// Start a new negotiation: irc and scr. The Configure-Naks and Configure-Rejects
+    /// counted in the previous one are forgotten.
+    fn start_configure(&mut self, tx: impl FnMut(Packet<'_>)) {
+        self.failures.clear();
+        self.irc_configure();
+        self.scr(tx);
+    }
+
+    /// Initialize-Restart-Count, before sending Configure-Requests
+    fn irc_configure(&mut self) {
+        self.restart_count = self.max_configure;
+        self.rejected = false;
+        self.not_converging = false;
+    }
+
+    /// Initialize-Restart-Count, before sending Terminate-Requests
+    fn irc_terminate(&mut self) {
+        self.restart_count = self.max_terminate;
+    }
+
+    /// Send-Configure-Request
+    fn scr(&mut self, mut tx: impl FnMut(Packet<'_>)) {
+        self.restart_count = self.restart_count.saturating_sub(1);
+        self.timer.start();
+        tx(self.send_configure_request())
+    }
+
+    /// Send-Terminate-Request
+    fn str(&mut self, mut tx: impl FnMut(Packet<'_>)) {
+        self.restart_count = self.restart_count.saturating_sub(1);
+        self.timer.start();
+        tx(self.send_terminate_request())
     }
 
     fn next_id(&mut self) -> u8 {
@@ -181,28 +744,29 @@ impl<P: Protocol> OptionFsm<P> {
     }
 
     fn send_configure_request(&mut self) -> Packet<'static> {
-        let mut opts = Vec::new();
+        let mut opts = Options::new();
 
         self.proto.own_options(|code, data| {
-            if opts.push(OptionVal::new(code, data)).is_err() {
-                panic!("tx ConfigureReq: too many options")
+            if opts.push(code, data).is_err() {
+                panic!("tx ConfigureReq: options too long")
             }
         });
 
+        let id = self.next_id();
+        self.req_id = Some(id);
+        unwrap!(self.req_options.resize_default(opts.buffer_len()));
+        opts.emit(&mut self.req_options);
+
         Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(
-                Code::ConfigureReq,
-                self.next_id(),
-                PPPPayload::Options(Options(opts)),
-            ),
+            payload: Payload::PPP(Code::ConfigureReq, id, PPPPayload::Options(opts)),
         }
     }
 
-    fn send_terminate_request<'a>(&mut self, reason: &'a mut [u8]) -> Packet<'a> {
+    fn send_terminate_request(&mut self) -> Packet<'static> {
         Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(Code::TerminateReq, self.next_id(), PPPPayload::Raw(reason)),
+            payload: Payload::PPP(Code::TerminateReq, self.next_id(), PPPPayload::Raw(&mut [])),
         }
     }
 
@@ -219,12 +783,20 @@ impl<P: Protocol> OptionFsm<P> {
             payload: Payload::PPP(
                 Code::CodeRej,
                 self.next_id(),
-                PPPPayload::Raw(&mut pkt[2..]),
+                PPPPayload::Raw(code_reject_data(pkt)),
             ),
         }
     }
 
     fn send_echo_response<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
+        // Truncate the data so the reply fits in a control packet.
+        let len = pkt.len().min(MAX_CONTROL_PACKET_LEN);
+        let pkt = &mut pkt[..len];
+        pkt[4..6].copy_from_slice(&(len as u16 - 2).to_be_bytes());
+        pkt[2] = Code::EchoReply.into();
+        if pkt.len() >= 10 {
+            pkt[6..10].copy_from_slice(&self.proto.magic_number().to_be_bytes());
+        }
         Packet {
             proto: self.proto.protocol(),
             payload: Payload::Raw(&mut pkt[2..]),
@@ -233,65 +805,158 @@ impl<P: Protocol> OptionFsm<P> {
 
     // TODO maybe this should be in PPP because it's only for LCP
     pub fn send_protocol_reject<'a>(&mut self, pkt: &'a mut [u8]) -> Packet<'a> {
+        // The rejected packet starts with its protocol field. Truncate it so the
+        // Protocol-Reject fits in a control packet.
+        let len = pkt.len().min(MAX_CONTROL_PACKET_LEN - 2 - 4);
         Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(Code::ProtocolRej, self.next_id(), PPPPayload::Raw(pkt)),
+            payload: Payload::PPP(
+                Code::ProtocolRej,
+                self.next_id(),
+                PPPPayload::Raw(&mut pkt[..len]),
+            ),
         }
     }
 
-    fn received_configure_req(&mut self, pkt: &[u8]) -> Packet<'static> {
-        let id = pkt[3];
+    /// Builds the reply to a Configure-Request. Returns None if the peer is not converging.
+    fn received_configure_req(
+        &mut self,
+        id: u8,
+        options: &[u8],
+    ) -> Result<Option<Packet<'static>>, MalformedError> {
         let mut code = Code::ConfigureAck;
 
-        if pkt.len() < 6 {
-            panic!("too short");
+        let mut opts = Options::new();
+        // Codes of the options in the reply.
+        let mut codes: Vec<u8, MaxOptions> = Vec::new();
+        let mut too_long = false;
+
+        // Check the whole packet before handing any of the options to the protocol.
+        let mut count = 0;
+        parse_options(options, |_, _| count += 1)?;
+        if count > codes.capacity() {
+            return Err(MalformedError::TooManyOptions);
         }
-        let pkt = &pkt[6..]; // skip header
 
-        let mut opts = Vec::new();
+        let failures = &self.failures;
+        let max_failure = self.max_failure;
+        let proto = &mut self.proto;
+
+        proto.peer_options_start();
+        parse_options(options, |ocode, odata| {
+            // After Max-Failure Configure-Naks of an option without an Ack, negotiation
+            // is not converging. Reject the option instead of Nak-ing it.
+            let nack_allowed = match failures.iter().find(|f| f.code == ocode) {
+                Some(f) => f.naks < max_failure,
+                None => failures.len() < failures.capacity(),
+            };
 
-        self.proto.peer_options_start();
-        parse_options(pkt, |ocode, odata| {
-            let (ret_code, data) = match self.proto.peer_option_received(ocode, odata) {
+            let (ret_code, data) = match proto.peer_option_received(ocode, odata) {
                 Verdict::Ack => (Code::ConfigureAck, odata),
-                Verdict::Nack(data) => (Code::ConfigureNack, data),
+                Verdict::Nack(data) if nack_allowed => (Code::ConfigureNack, data),
+                Verdict::Nack(_) => (Code::ConfigureRej, odata),
                 Verdict::Rej => (Code::ConfigureRej, odata),
             };
 
             if code < ret_code {
                 code = ret_code;
                 opts.clear();
+                codes.clear();
+                too_long = false;
             }
 
             if code == ret_code {
-                if opts.push(OptionVal::new(ocode, data)).is_err() {
-                    panic!("rx ConfigureReq: too many options")
+                too_long |= opts.push(ocode, data).is_err();
+                // Can't fail, the options were counted above.
+                let _ = codes.push(ocode);
+            }
+        })?;
+
+        if too_long {
+            return Err(MalformedError::TooLong);
+        }
+
+        if code == Code::ConfigureAck {
+            self.failures.clear();
+        } else {
+            for &ocode in &codes {
+                if !self.count_failure(ocode, code) {
+                    info!(
+                        "{:?}: peer keeps requesting rejected option {:?}, giving up",
+                        self.proto.protocol(),
+                        ocode
+                    );
+                    self.not_converging = true;
+                    return Ok(None);
                 }
             }
-        })
-        .unwrap();
+        }
 
-        Packet {
+        Ok(Some(Packet {
             proto: self.proto.protocol(),
-            payload: Payload::PPP(code, id, PPPPayload::Options(Options(opts))),
+            payload: Payload::PPP(code, id, PPPPayload::Options(opts)),
+        }))
+    }
+
+    /// Count a Configure-Nak or Configure-Reject of a peer option.
+    ///
+    /// Returns false if the option was already rejected Max-Failure times, or if there
+    /// are too many failing options to keep track of them.
+    fn count_failure(&mut self, code: u8, reply: Code) -> bool {
+        let i = match self.failures.iter().position(|f| f.code == code) {
+            Some(i) => i,
+            None => {
+                let f = OptionFailures {
+                    code,
+                    naks: 0,
+                    rejs: 0,
+                };
+                if self.failures.push(f).is_err() {
+                    return false;
+                }
+                self.failures.len() - 1
+            }
+        };
+
+        let f = &mut self.failures[i];
+        if reply == Code::ConfigureNack {
+            f.naks = f.naks.saturating_add(1);
+        } else {
+            if f.rejs >= self.max_failure {
+                return false;
+            }
+            f.rejs += 1;
         }
+        true
     }
 }
 
-fn parse_options(mut pkt: &[u8], mut f: impl FnMut(u8, &[u8])) -> Result<(), MalformedError> {
+/// Returns the data of a Code-Reject for `pkt`: the rejected packet without
+/// the protocol field, truncated so the Code-Reject fits in a control packet.
+pub(crate) fn code_reject_data(pkt: &mut [u8]) -> &mut [u8] {
+    let data = &mut pkt[2..];
+    let max_len = MAX_CONTROL_PACKET_LEN - 2 - 4;
+    let len = data.len().min(max_len);
+    &mut data[..len]
+}
+
+fn parse_options<'a>(
+    mut pkt: &'a [u8],
+    mut f: impl FnMut(u8, &'a [u8]),
+) -> Result<(), MalformedError> {
     while pkt.len() != 0 {
         if pkt.len() < 2 {
-            return Err(MalformedError);
+            return Err(MalformedError::BadOption);
         }
 
         let code = pkt[0];
         let len = pkt[1] as usize;
 
         if pkt.len() < len {
-            return Err(MalformedError);
+            return Err(MalformedError::BadOption);
         }
         if len < 2 {
-            return Err(MalformedError);
+            return Err(MalformedError::BadOption);
         }
 
         let data = &pkt[2..len];
@@ -302,6 +967,277 @@ fn parse_options(mut pkt: &[u8], mut f: impl FnMut(u8, &[u8])) -> Result<(), Mal
     Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct MalformedError;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::U8;
+
+    /// Acks option 1 and Naks any other, so Configure-Requests can be good or bad.
+    struct TestProto;
+
+    impl Protocol for TestProto {
+        fn protocol(&self) -> ProtocolType {
+            ProtocolType::LCP
+        }
+
+        fn own_options(&mut self, _f: impl FnMut(u8, &[u8])) {}
+        fn own_option_nacked(&mut self, _code: u8, _data: &[u8], _is_rej: bool) {}
+
+        fn peer_options_start(&mut self) {}
+        fn peer_option_received(&mut self, code: u8, _data: &[u8]) -> Verdict {
+            match code {
+                1 => Verdict::Ack,
+                _ => Verdict::Nack(&[0]),
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone)]
+    enum Event {
+        Up,
+        Down,
+        Open,
+        Close,
+        ToPlus,
+        ToMinus,
+        RcrPlus,
+        RcrMinus,
+        Rca,
+        Rcn,
+        Rtr,
+        Rta,
+        Ruc,
+        RxjPlus,
+        RxjMinus,
+        Rxr,
+    }
+
+    const STATES: [State; 10] = [
+        State::Initial,
+        State::Starting,
+        State::Closed,
+        State::Stopped,
+        State::Closing,
+        State::Stopping,
+        State::ReqSent,
+        State::AckReceived,
+        State::AckSent,
+        State::Opened,
+    ];
+
+    /// The state transition table of RFC 1661 section 4.1, as in states.txt. "-" means
+    /// the event is ignored.
+    ///
+    /// With the restart option, Open in Stopped does Down and Up. The other states
+    /// marked "r" don't use it. RCA and RCN are discarded in Ack-Rcvd and Opened, where
+    /// our request was acked already, see `reply_matches`.
+    #[rustfmt::skip]
+    const TABLE: [(Event, [&str; 10]); 16] = [
+        (Event::Up,       ["2", "irc,scr/6", "-", "-", "-", "-", "-", "-", "-", "-"]),
+        (Event::Down,     ["-", "-", "0", "tls/1", "0", "1", "1", "1", "1", "tld/1"]),
+        (Event::Open,     ["tls/1", "1", "irc,scr/6", "tls,irc,scr/6", "5", "5", "6", "7", "8", "9"]),
+        (Event::Close,    ["0", "tlf/0", "2", "2", "4", "4", "irc,str/4", "irc,str/4", "irc,str/4", "tld,irc,str/4"]),
+        (Event::ToPlus,   ["-", "-", "-", "-", "str/4", "str/5", "scr/6", "scr/6", "scr/8", "-"]),
+        (Event::ToMinus,  ["-", "-", "-", "-", "tlf/2", "tlf/3", "tlf/3", "tlf/3", "tlf/3", "-"]),
+        (Event::RcrPlus,  ["-", "-", "sta/2", "irc,scr,sca/8", "4", "5", "sca/8", "sca,tlu/9", "sca/8", "tld,scr,sca/8"]),
+        (Event::RcrMinus, ["-", "-", "sta/2", "irc,scr,scn/6", "4", "5", "scn/6", "scn/7", "scn/6", "tld,scr,scn/6"]),
+        (Event::Rca,      ["-", "-", "sta/2", "sta/3", "4", "5", "irc/7", "-", "irc,tlu/9", "-"]),
+        (Event::Rcn,      ["-", "-", "sta/2", "sta/3", "4", "5", "irc,scr/6", "-", "irc,scr/8", "-"]),
+        (Event::Rtr,      ["-", "-", "sta/2", "sta/3", "sta/4", "sta/5", "sta/6", "sta/6", "sta/6", "tld,zrc,sta/5"]),
+        (Event::Rta,      ["-", "-", "2", "3", "tlf/2", "tlf/3", "6", "6", "8", "tld,scr/6"]),
+        (Event::Ruc,      ["-", "-", "scj/2", "scj/3", "scj/4", "scj/5", "scj/6", "scj/7", "scj/8", "scj/9"]),
+        (Event::RxjPlus,  ["-", "-", "2", "3", "4", "5", "6", "6", "8", "9"]),
+        (Event::RxjMinus, ["-", "-", "tlf/2", "tlf/3", "tlf/2", "tlf/3", "tlf/3", "tlf/3", "tlf/3", "tld,irc,str/5"]),
+        (Event::Rxr,      ["-", "-", "2", "3", "4", "5", "6", "7", "8", "ser/9"]),
+    ];
+
+    /// Restart counter before each event. Send-Configure/Terminate-Request decrement it.
+    const RESTART_COUNT: u8 = 3;
+
+    fn action(pkt: &Packet<'_>) -> &'static str {
+        let code = match &pkt.payload {
+            Payload::PPP(code, _, _) => *code,
+            Payload::Raw(data) => Code::from(data[0]),
+        };
+        match code {
+            Code::ConfigureReq => "scr",
+            Code::ConfigureAck => "sca",
+            Code::ConfigureNack | Code::ConfigureRej => "scn",
+            Code::TerminateReq => "str",
+            Code::TerminateAck => "sta",
+            Code::CodeRej => "scj",
+            Code::EchoReply => "ser",
+            _ => "?",
+        }
+    }
+
+    fn layer_action(event: LayerEvent) -> &'static str {
+        match event {
+            LayerEvent::Up => "tlu",
+            LayerEvent::Down => "tld",
+            LayerEvent::Started => "tls",
+            LayerEvent::Finished => "tlf",
+        }
+    }
+
+    /// Automaton brought to `state` by the events leading there, so the outstanding
+    /// request, if any, is the one it really sent.
+    fn fsm_in(state: State) -> OptionFsm<TestProto> {
+        let mut fsm = OptionFsm::new(TestProto, &Config::default());
+        let mut tx = |_: Packet<'_>| {};
+        if !matches!(state, State::Initial | State::Closed) {
+            fsm.open(&mut tx);
+        }
+        if state != State::Starting {
+            fsm.up(&mut tx);
+        }
+        // Ack of the Configure-Request sent by Up.
+        let ack = [2, fsm.id, 0, 4];
+        match state {
+            State::Initial => fsm.down(),
+            State::Stopped => {
+                fsm.restart_count = 0;
+                fsm.timeout(&mut tx)
+            }
+            State::Closing => fsm.close(&mut tx),
+            State::AckReceived => receive(&mut fsm, &ack, &mut tx),
+            State::AckSent => receive(&mut fsm, &[1, 1, 0, 6, 1, 2], &mut tx),
+            State::Opened | State::Stopping => {
+                receive(&mut fsm, &[1, 1, 0, 6, 1, 2], &mut tx);
+                receive(&mut fsm, &ack, &mut tx);
+                match state {
+                    State::Stopping => receive(&mut fsm, &[5, 1, 0, 4], &mut tx),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        assert_eq!(fsm.state, state);
+        fsm
+    }
+
+    /// Handle `pkt`, without the protocol field.
+    fn receive(
+        fsm: &mut OptionFsm<TestProto>,
+        pkt: &[u8],
+        tx: impl FnMut(Packet<'_>),
+    ) -> Option<LayerEvent> {
+        let mut buf = [0; 16];
+        buf[..2].copy_from_slice(&u16::from(ProtocolType::LCP).to_be_bytes());
+        buf[2..][..pkt.len()].copy_from_slice(pkt);
+        buf[5] = pkt.len() as u8;
+        unwrap!(fsm.handle(&mut buf[..2 + pkt.len()], tx))
+    }
+
+    /// Run `event` in `state`. Returns the sent packets and the layer event.
+    fn run(
+        state: State,
+        event: Event,
+    ) -> (
+        OptionFsm<TestProto>,
+        Vec<&'static str, U8>,
+        Option<LayerEvent>,
+    ) {
+        let mut fsm = fsm_in(state);
+        fsm.restart_count = RESTART_COUNT;
+        // Replies carry the Identifier of our last request.
+        let id = fsm.id;
+
+        let mut sent = Vec::new();
+        let mut tx = |pkt: Packet<'_>| unwrap!(sent.push(action(&pkt)));
+        let mut handle = |fsm: &mut OptionFsm<TestProto>, pkt: &[u8]| receive(fsm, pkt, &mut tx);
+        let layer_event = match event {
+            Event::Up => fsm.up(&mut tx),
+            Event::Down => fsm.down(),
+            Event::Open => fsm.open(&mut tx),
+            Event::Close => fsm.close(&mut tx),
+            Event::ToPlus | Event::ToMinus => {
+                fsm.restart_count = matches!(event, Event::ToPlus) as u8;
+                fsm.timeout(&mut tx)
+            }
+            Event::RcrPlus => handle(&mut fsm, &[1, 1, 0, 6, 1, 2]),
+            Event::RcrMinus => handle(&mut fsm, &[1, 1, 0, 6, 2, 2]),
+            Event::Rca => handle(&mut fsm, &[2, id, 0, 4]),
+            Event::Rcn => handle(&mut fsm, &[3, id, 0, 4]),
+            Event::Rtr => handle(&mut fsm, &[5, 1, 0, 4]),
+            Event::Rta => handle(&mut fsm, &[6, 1, 0, 4]),
+            Event::Ruc => handle(&mut fsm, &[0x42, 1, 0, 4]),
+            Event::RxjPlus => handle(&mut fsm, &[7, 1, 0, 8, 9, 1, 0, 4]),
+            Event::RxjMinus => handle(&mut fsm, &[7, 1, 0, 8, 1, 1, 0, 4]),
+            Event::Rxr => handle(&mut fsm, &[9, 1, 0, 8, 0, 0, 0, 0]),
+        };
+        (fsm, sent, layer_event)
+    }
+
+    #[test]
+    fn state_transitions() {
+        let config = Config::default();
+        for (event, row) in TABLE.iter() {
+            for (&state, &cell) in STATES.iter().zip(row.iter()) {
+                let (actions, next) = match cell {
+                    "-" => ("", state),
+                    _ => match cell.rfind('/') {
+                        Some(i) => (&cell[..i], STATES[cell[i + 1..].parse::<usize>().unwrap()]),
+                        None => ("", STATES[cell.parse::<usize>().unwrap()]),
+                    },
+                };
+                let actions = || actions.split(',').filter(|a| !a.is_empty());
+
+                let (fsm, sent, layer_event) = run(state, *event);
+                let what = (event, state, cell);
+                assert_eq!(fsm.state, next, "{:?}", what);
+
+                let expected_sent: Vec<&str, U8> =
+                    actions().filter(|a| a.starts_with('s')).collect();
+                assert_eq!(sent, expected_sent, "{:?}", what);
+                let expected_event = actions().find(|a| a.starts_with("tl"));
+                assert_eq!(layer_event.map(layer_action), expected_event, "{:?}", what);
+
+                // irc and zrc, and the Configure/Terminate-Request sent after them.
+                let requests = sent.iter().filter(|&&a| a == "scr" || a == "str").count() as u8;
+                let count = if actions().any(|a| a == "zrc") {
+                    0
+                } else if actions().any(|a| a == "irc") {
+                    match sent.contains(&"str") {
+                        true => config.max_terminate - requests,
+                        false => config.max_configure - requests,
+                    }
+                } else if matches!(event, Event::ToPlus | Event::ToMinus) {
+                    matches!(event, Event::ToPlus) as u8 - requests
+                } else {
+                    RESTART_COUNT - requests
+                };
+                assert_eq!(fsm.restart_count, count, "{:?}", what);
+            }
+        }
+    }
+
+    #[test]
+    fn failures_counted_across_stopped() {
+        let config = Config {
+            max_failure: 2,
+            ..Default::default()
+        };
+        let mut fsm = OptionFsm::new(TestProto, &config);
+        fsm.state = State::Stopped;
+
+        // The Nak sent from Stopped counts, as the ones sent in ReqSent after it.
+        let mut replies: Vec<Code, U8> = Vec::new();
+        for _ in 0..3 {
+            receive(&mut fsm, &[1, 1, 0, 6, 2, 2], |pkt| {
+                if let Payload::PPP(code, _, _) = pkt.payload {
+                    if code != Code::ConfigureReq {
+                        unwrap!(replies.push(code));
+                    }
+                }
+            });
+        }
+        let expected = [Code::ConfigureNack, Code::ConfigureNack, Code::ConfigureRej];
+        assert_eq!(&replies[..], &expected[..]);
+
+        // A new negotiation starts from scratch.
+        fsm.state = State::Closed;
+        fsm.open(|_| {});
+        assert!(fsm.failures.is_empty());
+    }
+}
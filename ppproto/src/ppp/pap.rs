@@ -1,7 +1,10 @@
 use crate::fmt::{assert, *};
-use core::convert::TryInto;
 
-use crate::wire::{Code, PPPPayload, Packet, Payload, ProtocolType};
+use super::option_fsm::code_reject_data;
+use super::timer::Timer;
+use super::Config;
+use crate::time::{Duration, Instant};
+use crate::wire::{parse_header, Code, MalformedError, PPPPayload, Packet, Payload, ProtocolType};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -9,6 +12,8 @@ pub enum State {
     Closed,
     ReqSent,
     Opened,
+    /// No reply to Max-Configure Authenticate-Requests.
+    Failed,
 }
 pub struct PAP<'a> {
     state: State,
@@ -16,17 +21,27 @@ pub struct PAP<'a> {
 
     username: &'a [u8],
     password: &'a [u8],
+
+    timer: Timer,
+    restart_count: u8,
+    restart_interval: Duration,
+    max_configure: u8,
 }
 
 impl<'a> PAP<'a> {
-    pub fn new(username: &'a [u8], password: &'a [u8]) -> Self {
-        assert!(username.len() <= u8::MAX as usize);
-        assert!(password.len() <= u8::MAX as usize);
+    pub fn new(config: &Config<'a>) -> Self {
+        assert!(config.username.len() <= u8::MAX as usize);
+        assert!(config.password.len() <= u8::MAX as usize);
         Self {
             state: State::Closed,
             id: 1,
-            username,
-            password,
+            username: config.username,
+            password: config.password,
+
+            timer: Timer::Stopped,
+            restart_count: 0,
+            restart_interval: config.restart_interval,
+            max_configure: config.max_configure,
         }
     }
 
@@ -34,41 +49,68 @@ impl<'a> PAP<'a> {
         self.state
     }
 
-    pub fn open(&mut self) -> Packet<'_> {
+    pub fn open(&mut self) -> Packet<'a> {
         assert!(self.state == State::Closed);
         self.state = State::ReqSent;
+        self.restart_count = self.max_configure;
         self.send_configure_request()
     }
 
     pub fn close(&mut self) {
         self.state = State::Closed;
+        self.timer.stop();
     }
 
-    pub fn handle(&mut self, pkt: &mut [u8], mut tx: impl FnMut(Packet<'_>)) {
-        if pkt.len() < 6 {
-            info!("warn: too short");
+    /// Drive the restart timer, retransmitting the Authenticate-Request.
+    pub fn poll(&mut self, now: Instant, mut tx: impl FnMut(Packet<'_>)) {
+        if !self.timer.poll(now, self.restart_interval) || self.state != State::ReqSent {
             return;
         }
-        let code = Code::from(pkt[2]);
-        let _id = pkt[3];
-        let len = u16::from_be_bytes(pkt[4..6].try_into().unwrap()) as usize;
-        if len > pkt.len() {
-            info!("warn: len too short");
-            return;
+
+        if self.restart_count > 0 {
+            tx(self.send_configure_request())
+        } else {
+            info!("PAP: no reply from peer, giving up");
+            self.state = State::Failed;
         }
-        let _pkt = &mut pkt[..len + 2];
+    }
+
+    pub fn handle(
+        &mut self,
+        pkt: &mut [u8],
+        mut tx: impl FnMut(Packet<'_>),
+    ) -> Result<(), MalformedError> {
+        let (code, _id, data) = parse_header(pkt).ok_or(MalformedError::TooShort)?;
+        let len = 6 + data.len();
 
         info!("PAP: rx {:?}", code);
         let old_state = self.state;
         match (code, self.state) {
-            (Code::ConfigureAck, State::ReqSent) => self.state = State::Opened,
+            (Code::ConfigureAck, State::ReqSent) => {
+                self.state = State::Opened;
+                self.timer.stop();
+            }
             (Code::ConfigureNack, State::ReqSent) => tx(self.send_configure_request()),
-            _ => {}
+            // Authenticate-Request, Authenticate-Ack and Authenticate-Nak are the only PAP codes.
+            (Code::ConfigureReq, _) | (Code::ConfigureAck, _) | (Code::ConfigureNack, _) => {}
+            // Never answered with a Code-Reject, or two implementations would reject
+            // each other forever.
+            (Code::CodeRej, _) => info!("PAP: peer rejected a code"),
+            _ => {
+                info!("PAP: rejecting unknown code {:?}", pkt[2]);
+                tx(self.send_code_reject(&mut pkt[..len]))
+            }
         }
 
         if old_state != self.state {
             info!("PAP: state {:?} -> {:?}", old_state, self.state);
         }
+        Ok(())
+    }
+
+    /// When the Authenticate-Request must be retransmitted, if no reply arrived yet.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.timer.poll_at()
     }
 
     fn next_id(&mut self) -> u8 {
@@ -76,8 +118,21 @@ impl<'a> PAP<'a> {
         self.id
     }
 
+    fn send_code_reject<'p>(&mut self, pkt: &'p mut [u8]) -> Packet<'p> {
+        Packet {
+            proto: ProtocolType::PAP,
+            payload: Payload::PPP(
+                Code::CodeRej,
+                self.next_id(),
+                PPPPayload::Raw(code_reject_data(pkt)),
+            ),
+        }
+    }
+
     fn send_configure_request(&mut self) -> Packet<'a> {
         info!("PAP: tx {:?}", Code::ConfigureReq);
+        self.restart_count = self.restart_count.saturating_sub(1);
+        self.timer.start();
         Packet {
             proto: ProtocolType::PAP,
             payload: Payload::PPP(
@@ -0,0 +1,51 @@
+use crate::time::{Duration, Instant};
+
+/// Restart timer, shared by the option negotiation automaton and the auth protocols.
+///
+/// The timer can be started without knowing the current time. It is then armed on the
+/// next `poll`, which happens right after processing the event that started it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Timer {
+    Stopped,
+    Started,
+    Running(Instant),
+}
+
+impl Timer {
+    pub fn start(&mut self) {
+        *self = Timer::Started
+    }
+
+    pub fn stop(&mut self) {
+        *self = Timer::Stopped
+    }
+
+    /// Returns true if the timer has expired. An expired timer is stopped.
+    pub fn poll(&mut self, now: Instant, interval: Duration) -> bool {
+        match *self {
+            Timer::Stopped => false,
+            Timer::Started => {
+                *self = Timer::Running(now + interval);
+                false
+            }
+            Timer::Running(deadline) => {
+                if now >= deadline {
+                    *self = Timer::Stopped;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// The deadline of a running timer. A just started one must be polled right away to
+    /// get its deadline.
+    pub fn poll_at(&self) -> Option<Instant> {
+        match *self {
+            Timer::Stopped => None,
+            Timer::Started => Some(Instant::ZERO),
+            Timer::Running(deadline) => Some(deadline),
+        }
+    }
+}
@@ -1,3 +1,51 @@
+/// Frame Check Sequence, RFC 1662 appendix C.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fcs {
+    Fcs16,
+    /// 32-bit FCS, negotiated with the FCS-Alternatives option (RFC 1570).
+    Fcs32,
+}
+
+impl Fcs {
+    pub fn new(fcs32: bool) -> Self {
+        if fcs32 {
+            Self::Fcs32
+        } else {
+            Self::Fcs16
+        }
+    }
+
+    /// Length of the FCS field.
+    pub fn len(self) -> usize {
+        match self {
+            Self::Fcs16 => 2,
+            Self::Fcs32 => 4,
+        }
+    }
+
+    pub fn init(self) -> u32 {
+        match self {
+            Self::Fcs16 => 0xFFFF,
+            Self::Fcs32 => 0xFFFFFFFF,
+        }
+    }
+
+    pub fn update(self, crc: u32, data: &[u8]) -> u32 {
+        match self {
+            Self::Fcs16 => crc16(crc as u16, data) as u32,
+            Self::Fcs32 => crc32(crc, data),
+        }
+    }
+
+    /// Value of the CRC over a frame including its FCS, when the frame is good.
+    pub fn good(self) -> u32 {
+        match self {
+            Self::Fcs16 => 0xf0b8,
+            Self::Fcs32 => 0xdebb20e3,
+        }
+    }
+}
+
 pub fn crc16(mut seed: u16, data: &[u8]) -> u16 {
     for &b in data {
         let e = seed as u8 ^ b;
@@ -7,3 +55,66 @@ pub fn crc16(mut seed: u16, data: &[u8]) -> u16 {
     }
     seed
 }
+
+pub fn crc32(mut seed: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        seed ^= b as u32;
+        for _ in 0..8 {
+            seed = (seed >> 1) ^ (0xedb88320 & (seed & 1).wrapping_neg());
+        }
+    }
+    seed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame_reader::FrameReader;
+    use super::super::frame_writer::FrameWriter;
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn check_values() {
+        assert_eq!(!crc16(0xFFFF, CHECK), 0x906E);
+        assert_eq!(!crc32(0xFFFFFFFF, CHECK), 0xCBF43926);
+    }
+
+    #[test]
+    fn good_residue() {
+        for &fcs in &[Fcs::Fcs16, Fcs::Fcs32] {
+            // The FCS field is the complement of the CRC, least significant byte first.
+            let crc = fcs.update(fcs.init(), CHECK);
+            let field = (!crc).to_le_bytes();
+            assert_eq!(fcs.update(crc, &field[..fcs.len()]), fcs.good());
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let pkt = [0x00, 0x21, 0x45, 0x7e, 0x7d, 0x03, 0xff];
+        for &compressed in &[false, true] {
+            let mut wire = [0; 32];
+            let mut w = FrameWriter::new(&mut wire, 0xFFFFFFFF, &[0; 8]);
+            if compressed {
+                unwrap!(w.start_compressed(Fcs::Fcs32));
+            } else {
+                unwrap!(w.start(Fcs::Fcs32));
+            }
+            unwrap!(w.append(&pkt));
+            unwrap!(w.finish());
+            let len = w.len();
+
+            let mut r = FrameReader::new();
+            let mut buf = [0; 32];
+            assert_eq!(r.consume(&mut buf, &wire[..len], 0, Fcs::Fcs32), len);
+            let range = unwrap!(r.receive());
+            assert_eq!(buf[range], pkt);
+
+            // A frame with a 32-bit FCS doesn't pass the 16-bit check.
+            assert_eq!(r.consume(&mut buf, &wire[..len], 0, Fcs::Fcs16), len);
+            assert_eq!(r.receive(), None);
+            assert_eq!(r.link_counters().in_errors, 1);
+        }
+    }
+}
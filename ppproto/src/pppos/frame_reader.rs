@@ -1,6 +1,7 @@
 use core::ops::Range;
 
-use super::crc::crc16;
+use super::crc::Fcs;
+use crate::ppp::LinkCounters;
 
 #[derive(Copy, Clone, Debug)]
 enum State {
@@ -13,7 +14,16 @@ enum State {
 pub struct FrameReader {
     state: State,
     escape: bool,
+    /// The frame has no address and control fields.
+    compressed: bool,
     len: usize,
+    /// Length of the FCS of the received frame.
+    fcs_len: usize,
+    oversized: u32,
+    discarded_chars: u32,
+    in_packets: u32,
+    in_octets: u32,
+    in_errors: u32,
 }
 
 impl FrameReader {
@@ -21,7 +31,41 @@ impl FrameReader {
         Self {
             state: State::Start,
             escape: false,
+            compressed: false,
             len: 0,
+            fcs_len: 0,
+            oversized: 0,
+            discarded_chars: 0,
+            in_packets: 0,
+            in_octets: 0,
+            in_errors: 0,
+        }
+    }
+
+    /// Discard the partially received frame, if any.
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+        self.escape = false;
+        self.len = 0;
+    }
+
+    /// Number of frames dropped because they didn't fit in the buffer.
+    pub fn oversized(&self) -> u32 {
+        self.oversized
+    }
+
+    /// Number of unescaped control characters dropped because they are in the receive ACCM.
+    pub fn discarded_chars(&self) -> u32 {
+        self.discarded_chars
+    }
+
+    /// Counters for Link Quality Monitoring.
+    pub fn link_counters(&self) -> LinkCounters {
+        LinkCounters {
+            in_packets: self.in_packets,
+            in_octets: self.in_octets,
+            in_errors: self.in_errors,
+            in_discards: self.oversized,
         }
     }
 
@@ -31,47 +75,135 @@ impl FrameReader {
                 let len = self.len;
                 self.len = 0;
                 self.state = State::Address;
-                Some(1..len - 2)
+                Some(1..len - self.fcs_len)
             }
             _ => None,
         }
     }
 
-    pub fn consume(&mut self, buf: &mut [u8], data: &[u8]) -> usize {
+    /// Consume received data. `asyncmap` is the receive ACCM: control characters
+    /// flagged in it must arrive escaped, so unescaped ones are noise and are dropped.
+    /// `fcs` is the FCS the frames must carry.
+    pub fn consume(&mut self, buf: &mut [u8], data: &[u8], asyncmap: u32, fcs: Fcs) -> usize {
         for (i, &b) in data.iter().enumerate() {
             match (self.state, b) {
+                // When we have received a frame, do not consume more data until it's processed with receive()
+                (State::Complete, _) => return i,
+                (_, 0..=0x1f) if asyncmap & (1 << b) != 0 => {
+                    self.discarded_chars = self.discarded_chars.wrapping_add(1);
+                }
                 (State::Start, 0x7e) => self.state = State::Address,
                 (State::Start, _) => {}
-                (State::Address, 0xff) => self.state = State::Data,
-                (State::Address, 0x7e) => self.state = State::Address,
-                (State::Address, _) => self.state = State::Start,
-                (State::Data, 0x7e) => {
-                    // End of packet
-                    let ok = self.len >= 3
-                        && buf[0] == 0x03
-                        && crc16(0x00FF, &buf[..self.len]) == 0xf0b8;
-                    self.state = if ok { State::Complete } else { State::Address }
+                (State::Address, 0x7e) => {
+                    self.escape = false;
+                    self.state = State::Address;
                 }
-                (State::Data, 0x7d) => self.escape = true,
-                (State::Data, mut b) => {
-                    if self.escape {
-                        self.escape = false;
-                        b ^= 0x20;
+                (State::Address, 0x7d) | (State::Data, 0x7d) => self.escape = true,
+                (State::Address, b) => {
+                    // The address field may be escaped, if the peer escapes 0xff.
+                    let b = self.unescape(b);
+                    if b == 0xff {
+                        self.compressed = false;
+                        self.state = State::Data;
+                        continue;
                     }
-                    if self.len == usize::MAX || self.len >= buf.len() {
+
+                    // Address-and-Control-Field-Compression: the frame starts with the
+                    // protocol field. Put the control field in the buffer anyway, so the
+                    // packet is at the same offset for both kinds of frames.
+                    if buf.is_empty() {
                         self.state = State::Start;
-                        self.len = 0;
+                        continue;
+                    }
+                    buf[0] = 0x03;
+                    self.len = 1;
+                    self.compressed = true;
+                    self.state = State::Data;
+                    self.store(buf, b);
+                }
+                (State::Data, 0x7e) => {
+                    // End of packet
+                    // The two ends don't switch FCS at exactly the same time when LCP
+                    // goes up or down, so LCP frames are accepted with either.
+                    let other = match fcs {
+                        Fcs::Fcs16 => Fcs::Fcs32,
+                        Fcs::Fcs32 => Fcs::Fcs16,
+                    };
+                    let fcs = if self.check(buf, fcs) {
+                        Some(fcs)
+                    } else if self.is_lcp(buf) && self.check(buf, other) {
+                        Some(other)
+                    } else {
+                        None
+                    };
+                    self.escape = false;
+                    if let Some(fcs) = fcs {
+                        // The address field is not in the buffer, the control field may be a placeholder.
+                        let octets = if self.compressed {
+                            self.len - 1
+                        } else {
+                            self.len + 1
+                        };
+                        self.in_packets = self.in_packets.wrapping_add(1);
+                        self.in_octets = self.in_octets.wrapping_add(octets as u32);
+                        self.fcs_len = fcs.len();
+                        self.state = State::Complete;
                     } else {
-                        buf[self.len as usize] = b;
-                        self.len += 1;
+                        self.in_errors = self.in_errors.wrapping_add(1);
+                        self.state = State::Address;
+                        self.len = 0;
                     }
                 }
-                // When we have received a frame, do not consume more data until it's processed with receive()
-                (State::Complete, _) => return i,
+                (State::Data, b) => {
+                    let b = self.unescape(b);
+                    self.store(buf, b);
+                }
             }
         }
 
         // All consumed
         data.len()
     }
+
+    /// Check the FCS of the received frame.
+    fn check(&self, buf: &[u8], fcs: Fcs) -> bool {
+        if self.len < 1 + fcs.len() {
+            return false;
+        }
+        let crc = if self.compressed {
+            fcs.update(fcs.init(), &buf[1..self.len])
+        } else {
+            if buf[0] != 0x03 {
+                return false;
+            }
+            let crc = fcs.update(fcs.init(), &[0xff]);
+            fcs.update(crc, &buf[..self.len])
+        };
+        crc == fcs.good()
+    }
+
+    /// LCP frames always have the address, control and full protocol fields.
+    fn is_lcp(&self, buf: &[u8]) -> bool {
+        !self.compressed && self.len >= 3 && buf[1..3] == [0xc0, 0x21]
+    }
+
+    fn unescape(&mut self, b: u8) -> u8 {
+        if self.escape {
+            self.escape = false;
+            b ^ 0x20
+        } else {
+            b
+        }
+    }
+
+    fn store(&mut self, buf: &mut [u8], b: u8) {
+        if self.len == usize::MAX || self.len >= buf.len() {
+            self.oversized = self.oversized.wrapping_add(1);
+            self.state = State::Start;
+            self.len = 0;
+        } else {
+            buf[self.len as usize] = b;
+            self.len += 1;
+        }
+    }
 }
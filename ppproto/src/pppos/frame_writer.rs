@@ -1,51 +1,78 @@
-use super::crc::crc16;
+use super::crc::Fcs;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct BufferFullError;
 
+/// Set of characters to escape, one bit per character.
+pub type EscapeMap = [u32; 8];
+
 pub struct FrameWriter<'a> {
     buf: &'a mut [u8],
     len: usize,
-    crc: u16,
-    asyncmap: u32,
+    /// Number of frames written.
+    frames: u32,
+    crc: u32,
+    fcs: Fcs,
+    escape: EscapeMap,
 }
 
 impl<'a> FrameWriter<'a> {
-    pub fn new(buf: &'a mut [u8]) -> Self {
-        Self {
-            buf,
-            len: 0,
-            crc: 0,
-            asyncmap: 0xFFFFFFFF,
-        }
-    }
+    /// Create a writer escaping the control characters in `asyncmap`, plus the ones in `escape`.
+    pub fn new(buf: &'a mut [u8], asyncmap: u32, escape: &EscapeMap) -> Self {
+        let mut escape = *escape;
+        escape[0] |= asyncmap;
+        // Flag and control escape
+        escape[0x7d / 32] |= 1 << (0x7d % 32);
+        escape[0x7e / 32] |= 1 << (0x7e % 32);
 
-    pub fn new_with_asyncmap(buf: &'a mut [u8], asyncmap: u32) -> Self {
         Self {
             buf,
             len: 0,
+            frames: 0,
             crc: 0,
-            asyncmap,
+            fcs: Fcs::Fcs16,
+            escape,
         }
     }
 
-    pub fn len(self) -> usize {
+    pub fn len(&self) -> usize {
         self.len
     }
 
-    pub fn start(&mut self) -> Result<(), BufferFullError> {
-        self.crc = crc16(0xFFFF, &[0xFF, 0x03]);
-        self.append_raw(&[0x7e, 0xff])?;
-        self.append_escaped(&[0x03])?;
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Drop what was written after the first `len` bytes, such as a frame that didn't fit.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    pub fn start(&mut self, fcs: Fcs) -> Result<(), BufferFullError> {
+        self.fcs = fcs;
+        self.crc = fcs.update(fcs.init(), &[0xFF, 0x03]);
+        self.append_raw(&[0x7e])?;
+        self.append_escaped(&[0xff, 0x03])?;
+
+        Ok(())
+    }
+
+    /// Start a frame without the address and control fields, for
+    /// Address-and-Control-Field-Compression.
+    pub fn start_compressed(&mut self, fcs: Fcs) -> Result<(), BufferFullError> {
+        self.fcs = fcs;
+        self.crc = fcs.init();
+        self.append_raw(&[0x7e])?;
 
         Ok(())
     }
 
     pub fn finish(&mut self) -> Result<(), BufferFullError> {
-        let crc = self.crc ^ 0xFFFF;
-        self.append_escaped(&crc.to_le_bytes())?;
+        let crc = !self.crc;
+        self.append_escaped(&crc.to_le_bytes()[..self.fcs.len()])?;
         self.append_raw(&[0x7e])?;
+        self.frames += 1;
 
         Ok(())
     }
@@ -62,14 +89,7 @@ impl<'a> FrameWriter<'a> {
 
     fn append_escaped(&mut self, data: &[u8]) -> Result<(), BufferFullError> {
         for &b in data {
-            let escape = match b {
-                0..=0x1f => self.asyncmap & (1 << (b as u32)) != 0,
-                0x7d => true,
-                0x7e => true,
-                _ => false,
-            };
-
-            if escape {
+            if self.escape[b as usize / 32] & (1 << (b % 32)) != 0 {
                 self.append_raw(&[0x7d, b ^ 0x20])?;
             } else {
                 self.append_raw(&[b])?;
@@ -80,7 +100,7 @@ impl<'a> FrameWriter<'a> {
 
     pub fn append(&mut self, data: &[u8]) -> Result<(), BufferFullError> {
         self.append_escaped(data)?;
-        self.crc = crc16(self.crc, data);
+        self.crc = self.fcs.update(self.crc, data);
         Ok(())
     }
 }
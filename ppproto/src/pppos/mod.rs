@@ -2,19 +2,37 @@ mod crc;
 mod frame_reader;
 mod frame_writer;
 
+use crate::fmt::{assert, panic, *};
 use as_slice::AsMutSlice;
-use core::convert::TryInto;
+use core::cell::Cell;
 use core::ops::Range;
-use crate::fmt::{assert, panic, *};
 
+use self::crc::Fcs;
 use self::frame_reader::FrameReader;
-use self::frame_writer::FrameWriter;
-use crate::ppp::PPP;
-use crate::wire::{Packet, ProtocolType};
+use self::frame_writer::{EscapeMap, FrameWriter};
+use crate::ppp::{set_out_counters, PPP};
+use crate::time::{Duration, Instant};
+use crate::wire::{Code, Packet, Payload, ProtocolType};
 use crate::{Config, Status};
 
 pub use self::frame_writer::BufferFullError;
 
+/// Error returned by `PPPoS::send`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SendError {
+    /// The tx buffer is too small for the framed packet.
+    BufferFull,
+    /// The packet is larger than the MRU of the peer.
+    TooLarge,
+}
+
+impl From<BufferFullError> for SendError {
+    fn from(_: BufferFullError) -> Self {
+        Self::BufferFull
+    }
+}
+
 pub enum PPPoSAction<B> {
     None,
     Received(B, Range<usize>),
@@ -24,81 +42,236 @@ pub enum PPPoSAction<B> {
 pub struct PPPoS<'a, B: AsMutSlice<Element = u8>> {
     frame_reader: FrameReader,
     rx_buf: Option<B>,
+    /// Size of the last rx buffer, the MRU is derived from it.
+    rx_buf_len: usize,
+    /// Characters always escaped on transmit, from `Config::escape`.
+    tx_escape: EscapeMap,
+    /// Frames and octets transmitted, for Link Quality Monitoring.
+    out_packets: u32,
+    out_octets: u32,
+    /// Time of the last `poll_timed`.
+    now: Instant,
     ppp: PPP<'a>,
 }
 
 impl<'a, B: AsMutSlice<Element = u8>> PPPoS<'a, B> {
     pub fn new(config: Config<'a>) -> Self {
+        let mut tx_escape = [0; 8];
+        for &b in config.escape {
+            // Escaping these would send a control character or the flag.
+            assert!(
+                !matches!(b, 0x20..=0x3f | 0x5e),
+                "character {:?} can't be escaped",
+                b
+            );
+            tx_escape[b as usize / 32] |= 1 << (b % 32);
+        }
+
         Self {
             frame_reader: FrameReader::new(),
             rx_buf: None,
+            rx_buf_len: 0,
+            tx_escape,
+            out_packets: 0,
+            out_octets: 0,
+            now: Instant::ZERO,
             ppp: PPP::new(config),
         }
     }
 
     pub fn status(&self) -> Status {
-        self.ppp.status()
+        let mut status = self.ppp.status();
+        status.counters.oversized_frames = self.frame_reader.oversized();
+        status.counters.discarded_chars = self.frame_reader.discarded_chars();
+        status
     }
 
     pub fn open(&mut self) -> Result<(), crate::InvalidStateError> {
         self.ppp.open()
     }
 
+    /// Notify that the lower layer is up, for example after the modem reports carrier.
+    ///
+    /// If the link is open, LCP negotiation starts on the next `poll`. The lower layer
+    /// is assumed to be up when the `PPPoS` is created.
+    pub fn lower_layer_up(&mut self) {
+        self.ppp.lower_up()
+    }
+
+    /// Notify that the lower layer went down, for example on modem carrier loss.
+    ///
+    /// Partially received frames are discarded and all protocols are taken down. If the
+    /// link is open, it is established again after `lower_layer_up` is called.
+    pub fn lower_layer_down(&mut self) {
+        self.frame_reader.reset();
+        self.ppp.lower_down()
+    }
+
+    /// Gracefully close the link.
+    ///
+    /// Terminate-Requests are sent to the peer on the next `poll`. The link is
+    /// closed when `status().phase` becomes `Phase::Dead`.
+    pub fn close(&mut self) -> Result<(), crate::InvalidStateError> {
+        self.ppp.close()
+    }
+
+    /// Send an LCP Echo-Request to measure the round-trip time to the peer.
+    ///
+    /// The request is sent on the next `poll`. Once the peer replies, the round-trip time
+    /// is returned by `ping_rtt`. LCP must be open, IPv4CP doesn't need to be.
+    pub fn ping(&mut self) -> Result<(), crate::InvalidStateError> {
+        self.ppp.ping()
+    }
+
+    /// Return the round-trip time of the last `ping`, or `None` if its reply didn't arrive yet.
+    pub fn ping_rtt(&self) -> Option<Duration> {
+        self.ppp.ping_rtt()
+    }
+
+    /// Return the MPPE master key (RFC 3079 section 3.4), once the link is authenticated
+    /// with MS-CHAPv2. Used to derive the MPPE session keys.
+    ///
+    /// Returns `None` with other authentication protocols, and once the link goes down.
+    pub fn master_key(&self) -> Option<[u8; 16]> {
+        self.ppp.master_key()
+    }
+
+    /// Return the EAP Master Session Key (RFC 3748 section 7.10), once the link is
+    /// authenticated with an EAP method that derives one, such as EAP-TLS.
+    ///
+    /// Returns `None` with other authentication protocols, and once the link goes down.
+    pub fn eap_msk(&self) -> Option<[u8; 64]> {
+        self.ppp.eap_msk()
+    }
+
     pub fn has_rx_buf(&self) -> bool {
         self.rx_buf.is_some()
     }
 
-    pub fn put_rx_buf(&mut self, rx_buf: B) {
+    /// Give a buffer for received frames.
+    ///
+    /// The MRU requested to the peer is derived from the size of the buffer. It takes
+    /// effect the next time LCP is negotiated.
+    pub fn put_rx_buf(&mut self, mut rx_buf: B) {
         if self.rx_buf.is_some() {
             panic!("called put_rx_buf when we already have a buffer.")
         }
 
+        // Buffers are usually all the same size, only the first one or a resized one
+        // changes the MRU. Setting it again would undo a Configure-Nak from the peer.
+        let len = rx_buf.as_mut_slice().len();
+        if len != self.rx_buf_len {
+            self.rx_buf_len = len;
+            // The buffer holds the control field, protocol, information and FCS. Leave
+            // room for a 32-bit FCS if we may negotiate it.
+            let fcs = Fcs::new(self.ppp.lcp.proto().fcs32);
+            let mru = len.saturating_sub(1 + 2 + fcs.len());
+            let mru = mru.min(u16::MAX as usize) as u16;
+            self.ppp.lcp.proto_mut().set_max_mru(mru);
+        }
+
         self.rx_buf = Some(rx_buf)
     }
 
+    /// Process received data and generate data to be send, without advancing the time.
+    ///
+    /// Same as `poll_timed` with the time of its last call, so no timer expires: nothing
+    /// is retransmitted and no keepalives are sent. Use `poll_timed` instead.
+    pub fn poll(&mut self, tx_buf: &mut [u8]) -> PPPoSAction<B> {
+        self.poll_timed(self.now, tx_buf)
+    }
+
     /// Process received data and generate data to be send.
     ///
     /// Action::Received is returned when an IP packet is received. You must then pass the packet
     /// to higher layers for processing.
     ///
     /// You must provide buffer space for data to be transmitted, and transmit the returned slice
-    /// over the serial connection if Action::Transmit is returned.
-    pub fn poll(&mut self, tx_buf: &mut [u8]) -> PPPoSAction<B> {
-        let mut w = FrameWriter::new(tx_buf);
+    /// over the serial connection if Action::Transmit is returned. EAP-TLS sends frames as
+    /// large as the peer's MRU, or as fit in `tx_buf` if it's smaller.
+    ///
+    /// `now` is the current time, used to drive retransmissions.
+    pub fn poll_timed(&mut self, now: Instant, tx_buf: &mut [u8]) -> PPPoSAction<B> {
+        self.now = now;
+        // Worst case, with every byte escaped: flags, address and control fields, protocol
+        // field and 32-bit FCS around the packet.
+        let max_len = tx_buf.len().saturating_sub(2 + 2 * (2 + 2 + 4)) / 2;
+        // Control packets escape all control characters, they may be sent before the
+        // ACCM is negotiated.
+        let mut w = FrameWriter::new(tx_buf, 0xFFFFFFFF, &self.tx_escape);
+        // The FCS changes when LCP goes up or down, which can happen while handling a packet.
+        let fcs = Cell::new(Fcs::new(self.ppp.tx_fcs32()));
+        let (out_packets, out_octets) = (self.out_packets, self.out_octets);
 
-        let buf = unwrap!(self.rx_buf.as_mut(), "called poll() without an rx_buf").as_mut_slice();
+        let buf = unwrap!(
+            self.rx_buf.as_mut(),
+            "called poll_timed() without an rx_buf"
+        )
+        .as_mut_slice();
 
-        let mut tx = |pkt: Packet<'_>| {
+        let mut tx = |mut pkt: Packet<'_>| {
             //info!("tx: {:?}", pkt);
 
-            let mut buf = [0; 128];
-            let len = pkt.buffer_len();
-            assert!(len <= buf.len());
-            pkt.emit(&mut buf[..len]);
+            // Configure packets are only sent while LCP is not up, with the default FCS.
+            let fcs = match pkt.payload {
+                Payload::PPP(
+                    Code::ConfigureReq
+                    | Code::ConfigureAck
+                    | Code::ConfigureNack
+                    | Code::ConfigureRej,
+                    ..,
+                ) if pkt.proto == ProtocolType::LCP => Fcs::Fcs16,
+                _ => fcs.get(),
+            };
 
-            w.start().unwrap();
-            w.append(&mut buf[..len]).unwrap();
-            w.finish().unwrap();
+            if pkt.proto == ProtocolType::LQR {
+                if let Payload::Raw(data) = &mut pkt.payload {
+                    // The report counts itself, and all the frames sent before it.
+                    let packets = out_packets.wrapping_add(w.frames()).wrapping_add(1);
+                    let octets = out_octets.wrapping_add(w.len() as u32);
+                    set_out_counters(data, packets, octets);
+                }
+            }
+
+            let start = w.len();
+            let res = w
+                .start(fcs)
+                .and_then(|_| pkt.emit_to(|data| w.append(data)));
+            if res.and_then(|_| w.finish()).is_err() {
+                info!("tx: dropping {:?} packet, tx_buf is full", pkt.proto);
+                w.truncate(start);
+            }
         };
 
         // Handle input
-        if let Some(range) = self.frame_reader.receive() {
+        if let Some(mut range) = self.frame_reader.receive() {
+            // Protocol-Field-Compression: a 1-byte protocol field has an odd first byte.
+            // Expand it in place over the control field, which comes right before it.
+            if !range.is_empty() && buf[range.start] & 1 == 1 {
+                range.start -= 1;
+                buf[range.start] = 0;
+            }
+
             let pkt = &mut buf[range.clone()];
-            let proto = u16::from_be_bytes(pkt[0..2].try_into().unwrap());
-            match proto.into() {
-                ProtocolType::IPv4 => {
-                    return PPPoSAction::Received(
-                        self.rx_buf.take().unwrap(),
-                        (range.start + 2)..range.end,
-                    )
-                }
-                _ => self.ppp.received(pkt, &mut tx),
+            if pkt.len() >= 2 && u16::from_be_bytes([pkt[0], pkt[1]]) == ProtocolType::IPv4.into() {
+                return PPPoSAction::Received(
+                    self.rx_buf.take().unwrap(),
+                    (range.start + 2)..range.end,
+                );
             }
+            // Malformed packets are dropped, and counted in the status.
+            self.ppp
+                .set_link_counters(self.frame_reader.link_counters());
+            self.ppp.set_max_tx_len(max_len);
+            let _ = self.ppp.received(now, pkt, &mut tx);
         }
 
-        self.ppp.poll(tx);
+        fcs.set(Fcs::new(self.ppp.tx_fcs32()));
+        self.ppp.poll(now, tx);
 
         let r = w.len();
+        self.out_packets = self.out_packets.wrapping_add(w.frames());
+        self.out_octets = self.out_octets.wrapping_add(r as u32);
         if r == 0 {
             PPPoSAction::None
         } else {
@@ -106,19 +279,54 @@ impl<'a, B: AsMutSlice<Element = u8>> PPPoS<'a, B> {
         }
     }
 
+    /// Return the instant at which `poll_timed` should be called next, even if no data is
+    /// received.
+    ///
+    /// Returns `None` if no timers are running. Returns an instant in the past
+    /// if `poll_timed` should be called right away.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.ppp.poll_at()
+    }
+
+    /// Return how long to wait before calling `poll_timed` again, even if no data is received.
+    ///
+    /// Returns `None` if no timers are running, and a zero duration if `poll_timed`
+    /// should be called right away.
+    pub fn poll_delay(&self, now: Instant) -> Option<Duration> {
+        self.poll_at().map(|at| at - now)
+    }
+
     /// Send an IP packet.
     ///
     /// You must provide buffer space for the data to be transmitted, and transmit the returned
     /// slice over the serial connection.
-    pub fn send(&mut self, pkt: &[u8], tx_buf: &mut [u8]) -> Result<usize, BufferFullError> {
+    ///
+    /// Packets larger than the MRU of the peer, reported as `Status::mtu`, are refused.
+    pub fn send(&mut self, pkt: &[u8], tx_buf: &mut [u8]) -> Result<usize, SendError> {
         // TODO check IPv4CP is up
 
-        let mut w = FrameWriter::new_with_asyncmap(tx_buf, self.ppp.lcp.proto().asyncmap_remote);
+        if pkt.len() > self.ppp.lcp.proto().mru_remote as usize {
+            return Err(SendError::TooLarge);
+        }
+
+        let lcp = self.ppp.lcp.proto();
+        let mut w = FrameWriter::new(tx_buf, lcp.asyncmap_remote, &self.tx_escape);
         let proto: u16 = ProtocolType::IPv4.into();
-        w.start()?;
-        w.append(&proto.to_be_bytes())?;
+        let fcs = Fcs::new(self.ppp.tx_fcs32());
+        if lcp.acfc_remote {
+            w.start_compressed(fcs)?;
+        } else {
+            w.start(fcs)?;
+        }
+        if lcp.pfc_remote {
+            w.append(&[proto as u8])?;
+        } else {
+            w.append(&proto.to_be_bytes())?;
+        }
         w.append(pkt)?;
         w.finish()?;
+        self.out_packets = self.out_packets.wrapping_add(1);
+        self.out_octets = self.out_octets.wrapping_add(w.len() as u32);
         Ok(w.len())
     }
 
@@ -130,6 +338,303 @@ impl<'a, B: AsMutSlice<Element = u8>> PPPoS<'a, B> {
     /// must be called again with the remaining data.
     pub fn consume(&mut self, data: &[u8]) -> usize {
         let buf = unwrap!(self.rx_buf.as_mut(), "called consume() without an rx_buf");
-        self.frame_reader.consume(buf.as_mut_slice(), data)
+        let asyncmap = self.ppp.rx_asyncmap();
+        let fcs = Fcs::new(self.ppp.rx_fcs32());
+        self.frame_reader
+            .consume(buf.as_mut_slice(), data, asyncmap, fcs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::consts::*;
+    use heapless::Vec;
+
+    use super::*;
+    use crate::Phase;
+
+    type P = PPPoS<'static, [u8; 256]>;
+    /// A packet, or a frame without its flags and escapes.
+    type Pkt = Vec<u8, U256>;
+    /// Bytes on the serial line.
+    type Wire = Vec<u8, U1024>;
+
+    /// An IPv4 packet, with characters that are escaped in some cases.
+    const IP: [u8; 8] = [0x45, 0x00, 0x11, 0x7e, 0x7d, 0x91, 0xff, 0x13];
+
+    fn pppos(config: Config<'static>) -> P {
+        let mut pppos = PPPoS::new(config);
+        pppos.put_rx_buf([0; 256]);
+        pppos
+    }
+
+    /// Feed `wire` to `pppos` and poll it, returning the IPv4 packets received and the
+    /// bytes sent.
+    fn receive(pppos: &mut P, mut wire: &[u8]) -> (Vec<Pkt, U4>, Wire) {
+        let mut received = Vec::new();
+        let mut sent = Wire::new();
+        loop {
+            let n = pppos.consume(wire);
+            wire = &wire[n..];
+            let mut tx_buf = [0; 512];
+            loop {
+                match pppos.poll(&mut tx_buf) {
+                    PPPoSAction::None => break,
+                    PPPoSAction::Transmit(n) => unwrap!(sent.extend_from_slice(&tx_buf[..n])),
+                    PPPoSAction::Received(buf, range) => {
+                        unwrap!(received.push(unwrap!(Pkt::from_slice(&buf[range]))));
+                        pppos.put_rx_buf(buf);
+                    }
+                }
+            }
+            if wire.is_empty() {
+                return (received, sent);
+            }
+        }
+    }
+
+    /// Send `pkt` from `pppos`, returning the bytes sent.
+    fn send(pppos: &mut P, pkt: &[u8]) -> Wire {
+        let mut tx_buf = [0; 64];
+        let n = unwrap!(pppos.send(pkt, &mut tx_buf));
+        unwrap!(Wire::from_slice(&tx_buf[..n]))
+    }
+
+    /// Split the bytes sent into frames, without the flags and escapes.
+    fn frames(wire: &[u8]) -> Vec<Pkt, U8> {
+        let mut frames = Vec::new();
+        let mut frame = Pkt::new();
+        let mut escape = false;
+        for &b in wire {
+            match b {
+                0x7e if frame.is_empty() => {}
+                0x7e => unwrap!(frames.push(core::mem::replace(&mut frame, Pkt::new()))),
+                0x7d => escape = true,
+                _ if escape => {
+                    unwrap!(frame.push(b ^ 0x20));
+                    escape = false;
+                }
+                _ => unwrap!(frame.push(b)),
+            }
+        }
+        frames
+    }
+
+    /// Open the link on both ends, and exchange frames until LCP is up.
+    fn connect(a: &mut P, b: &mut P) {
+        unwrap!(a.open());
+        unwrap!(b.open());
+        // Both send their Configure-Request first, a packet received before is dropped.
+        let (_, mut ab) = receive(a, &[]);
+        let (_, mut ba) = receive(b, &[]);
+        for _ in 0..5 {
+            let (_, a_sent) = receive(a, &ba);
+            ba = receive(b, &ab).1;
+            ab = a_sent;
+        }
+        for pppos in [a, b].iter() {
+            let phase = pppos.status().phase;
+            assert!(matches!(phase, Phase::Network | Phase::Open));
+        }
+    }
+
+    #[test]
+    fn pfc_round_trip() {
+        for &pfc in &[false, true] {
+            let mut a = pppos(Config::default());
+            let mut b = pppos(Config::default());
+            a.ppp.lcp.proto_mut().pfc_remote = pfc;
+
+            let wire = send(&mut a, &IP);
+            let frame = &frames(&wire)[0];
+            let proto: &[u8] = if pfc { &[0x21] } else { &[0x00, 0x21] };
+            assert_eq!(frame[..2], [0xff, 0x03]);
+            assert_eq!(frame[2..][..proto.len()], *proto);
+
+            // The protocol field is expanded in place, the packet ends up at the same offset.
+            let (received, sent) = receive(&mut b, &wire);
+            assert_eq!(received[..], [unwrap!(Pkt::from_slice(&IP))]);
+            assert_eq!(sent.len(), 0);
+        }
+    }
+
+    #[test]
+    fn acfc_round_trip() {
+        for &(acfc, pfc) in &[(false, false), (true, false), (true, true)] {
+            let mut a = pppos(Config::default());
+            let mut b = pppos(Config::default());
+            a.ppp.lcp.proto_mut().acfc_remote = acfc;
+            a.ppp.lcp.proto_mut().pfc_remote = pfc;
+
+            let wire = send(&mut a, &IP);
+            let frame = &frames(&wire)[0];
+            let header: &[u8] = match (acfc, pfc) {
+                (false, _) => &[0xff, 0x03, 0x00, 0x21],
+                (true, false) => &[0x00, 0x21],
+                (true, true) => &[0x21],
+            };
+            assert_eq!(frame[..header.len()], *header);
+            assert_eq!(frame[header.len()..][..IP.len()], IP);
+
+            let (received, _) = receive(&mut b, &wire);
+            assert_eq!(received[..], [unwrap!(Pkt::from_slice(&IP))]);
+        }
+    }
+
+    #[test]
+    fn lcp_never_compressed() {
+        let mut a = pppos(Config::default());
+        let mut b = pppos(Config::default());
+        connect(&mut a, &mut b);
+        let lcp = a.ppp.lcp.proto();
+        assert!(lcp.acfc_remote && lcp.pfc_remote);
+
+        let wire = send(&mut a, &IP);
+        assert_eq!(frames(&wire)[0][0], 0x21);
+
+        unwrap!(a.ping());
+        let (_, wire) = receive(&mut a, &[]);
+        let sent = frames(&wire);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][..5], [0xff, 0x03, 0xc0, 0x21, Code::EchoReq.into()]);
+
+        // The peer replies, ACFC and PFC don't apply to LCP either.
+        let (_, wire) = receive(&mut b, &wire);
+        let frame = &frames(&wire)[0];
+        assert_eq!(frame[..5], [0xff, 0x03, 0xc0, 0x21, Code::EchoReply.into()]);
+        receive(&mut a, &wire);
+        assert!(a.ping_rtt().is_some());
+    }
+
+    #[test]
+    fn accm_discard() {
+        let mut a = pppos(Config::default());
+        let mut b = pppos(Config::default());
+
+        // Before LCP is up, all control characters must arrive escaped. Unescaped ones,
+        // such as XON and XOFF, are dropped.
+        let wire = send(&mut a, &IP);
+        let mut noisy = Wire::new();
+        for (i, &c) in wire.iter().enumerate() {
+            unwrap!(noisy.push(c));
+            if i % 4 == 1 {
+                unwrap!(noisy.push(if i % 8 == 1 { 0x11 } else { 0x13 }));
+            }
+        }
+        let discarded = (noisy.len() - wire.len()) as u32;
+        let (received, _) = receive(&mut b, &noisy);
+        assert_eq!(received[..], [unwrap!(Pkt::from_slice(&IP))]);
+        assert_eq!(b.status().counters.discarded_chars, discarded);
+
+        // Once LCP is up, the negotiated ACCM is empty and they are data.
+        connect(&mut a, &mut b);
+        let wire = send(&mut a, &IP);
+        assert!(wire.contains(&0x11) && wire.contains(&0x13));
+        let (received, _) = receive(&mut b, &wire);
+        assert_eq!(received[..], [unwrap!(Pkt::from_slice(&IP))]);
+        assert_eq!(b.status().counters.discarded_chars, discarded);
+    }
+
+    #[test]
+    fn escape() {
+        let mut a = pppos(Config {
+            escape: &[0x91, 0x93],
+            ..Config::default()
+        });
+        let mut b = pppos(Config::default());
+        connect(&mut a, &mut b);
+
+        let ip = [0x45, 0x91, 0x11, 0x93, 0x13];
+        let wire = send(&mut a, &ip);
+        assert!(!wire.contains(&0x91) && !wire.contains(&0x93));
+        assert!(wire.windows(2).any(|w| w == [0x7d, 0xb1]));
+        assert!(wire.windows(2).any(|w| w == [0x7d, 0xb3]));
+        // The peer's ACCM is empty, control characters are sent as is.
+        assert!(wire.contains(&0x11) && wire.contains(&0x13));
+
+        let (received, _) = receive(&mut b, &wire);
+        assert_eq!(received[..], [unwrap!(Pkt::from_slice(&ip))]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn escape_invalid() {
+        pppos(Config {
+            escape: &[0x5e],
+            ..Config::default()
+        });
+    }
+
+    #[test]
+    fn lcp_configure_fcs16() {
+        let config = || Config {
+            fcs32: true,
+            ..Config::default()
+        };
+        let mut a = pppos(config());
+        let mut b = pppos(config());
+        connect(&mut a, &mut b);
+        assert!(a.ppp.tx_fcs32());
+        let fcs_ok = |frame: &[u8], fcs: Fcs| fcs.update(fcs.init(), frame) == fcs.good();
+        let wire = send(&mut a, &IP);
+        assert!(fcs_ok(&frames(&wire)[0], Fcs::Fcs32));
+
+        // The peer renegotiates. LCP goes down while handling its Configure-Request,
+        // our Configure-Request and Configure-Ack must have the default FCS.
+        let mut wire = [0; 32];
+        let mut w = FrameWriter::new(&mut wire, 0xFFFFFFFF, &[0; 8]);
+        unwrap!(w.start(Fcs::Fcs16));
+        unwrap!(w.append(&[0xc0, 0x21, Code::ConfigureReq.into(), 9, 0, 4]));
+        unwrap!(w.finish());
+        let len = w.len();
+        let (_, wire) = receive(&mut a, &wire[..len]);
+        let sent = frames(&wire);
+        assert_eq!(sent.len(), 2);
+        for (frame, &code) in sent.iter().zip(&[Code::ConfigureReq, Code::ConfigureAck]) {
+            assert_eq!(frame[..5], [0xff, 0x03, 0xc0, 0x21, code.into()]);
+            assert!(fcs_ok(frame, Fcs::Fcs16));
+        }
+    }
+
+    #[test]
+    fn lqr_out_counters() {
+        let config = || Config {
+            lqr_interval: Some(Duration::from_secs(10)),
+            ..Config::default()
+        };
+        let mut a = pppos(config());
+        let mut b = pppos(config());
+        connect(&mut a, &mut b);
+        send(&mut a, &IP);
+        let (out_packets, out_octets) = (a.out_packets, a.out_octets);
+
+        let mut tx_buf = [0; 512];
+        let now = Instant::ZERO + Duration::from_secs(10);
+        let len = match a.poll_timed(now, &mut tx_buf) {
+            PPPoSAction::Transmit(len) => len,
+            _ => unreachable!(),
+        };
+        // IPv4CP may be retransmitting too, find the report.
+        let sent = frames(&tx_buf[..len]);
+        let (i, report) = unwrap!(sent
+            .iter()
+            .enumerate()
+            .find(|(_, frame)| frame[2..4] == [0xc0, 0x25]));
+        // Each frame has both flags.
+        let (start, _) = unwrap!(tx_buf[..len]
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == 0x7e)
+            .nth(2 * i));
+
+        // The report counts itself, and all the frames sent before it.
+        let field = |n: usize| {
+            let f = &report[4 + n * 4..];
+            u32::from_be_bytes([f[0], f[1], f[2], f[3]])
+        };
+        assert_eq!(field(10), out_packets + i as u32 + 1);
+        assert_eq!(field(11), out_octets + start as u32);
+        assert_eq!(a.out_packets, out_packets + sent.len() as u32);
+        assert_eq!(a.out_octets, out_octets + len as u32);
     }
 }
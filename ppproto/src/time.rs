@@ -0,0 +1,90 @@
+use core::ops::{Add, Sub};
+
+/// A point in time, with millisecond resolution.
+///
+/// The epoch is arbitrary: it only has to be the same for all instants passed
+/// to the same `PPPoS` instance. On embedded targets this is typically the
+/// uptime of the device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Instant {
+    millis: u64,
+}
+
+impl Instant {
+    pub const ZERO: Instant = Instant::from_millis(0);
+
+    pub const fn from_millis(millis: u64) -> Self {
+        Self { millis }
+    }
+
+    pub const fn from_secs(secs: u64) -> Self {
+        Self {
+            millis: secs * 1000,
+        }
+    }
+
+    pub const fn total_millis(&self) -> u64 {
+        self.millis
+    }
+
+    /// Returns the current time, as milliseconds since the Unix epoch.
+    #[cfg(feature = "std")]
+    pub fn now() -> Self {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system time before Unix epoch");
+        Self::from_millis(elapsed.as_millis() as u64)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant::from_millis(self.millis.saturating_add(rhs.millis))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        Duration::from_millis(self.millis.saturating_sub(rhs.millis))
+    }
+}
+
+/// A relative amount of time, with millisecond resolution.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Duration {
+    millis: u64,
+}
+
+impl Duration {
+    pub const fn from_millis(millis: u64) -> Self {
+        Self { millis }
+    }
+
+    pub const fn from_secs(secs: u64) -> Self {
+        Self {
+            millis: secs * 1000,
+        }
+    }
+
+    pub const fn total_millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl From<core::time::Duration> for Duration {
+    fn from(d: core::time::Duration) -> Self {
+        Self::from_millis(d.as_millis() as u64)
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(d: Duration) -> Self {
+        core::time::Duration::from_millis(d.millis)
+    }
+}
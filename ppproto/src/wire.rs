@@ -4,8 +4,31 @@ use heapless::consts::*;
 use heapless::Vec;
 use num_enum::{FromPrimitive, IntoPrimitive};
 
-pub type MaxOptions = U6;
-pub type MaxOptionLen = U4;
+pub type MaxOptions = U8;
+/// Maximum encoded length of the options of our own Configure-Requests.
+pub type MaxOptionsBufLen = U64;
+/// Maximum encoded length of the options of a Configure packet we send: a control packet
+/// minus the protocol field and the header.
+pub type MaxOptionsLen = U122;
+
+/// Maximum length of the control protocol packets we send, including the protocol field.
+pub const MAX_CONTROL_PACKET_LEN: usize = 128;
+
+/// Why a received packet was dropped.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MalformedError {
+    /// The packet is shorter than its header, or than its Length field.
+    TooShort,
+    /// The Length field is larger than the packet.
+    BadLength,
+    /// An option has a Length field that is too short, or larger than the packet.
+    BadOption,
+    /// The packet has more options than we can handle.
+    TooManyOptions,
+    /// The reply to the packet would not fit in a control packet.
+    TooLong,
+}
 
 #[derive(FromPrimitive, IntoPrimitive, Copy, Clone, Eq, PartialEq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -17,6 +40,12 @@ pub enum ProtocolType {
     LCP = 0xc021,
     /// Password Authentication Protocol, rfc1334
     PAP = 0xc023,
+    /// Challenge Handshake Authentication Protocol, rfc1994
+    CHAP = 0xc223,
+    /// Extensible Authentication Protocol, rfc3748
+    EAP = 0xc227,
+    /// Link Quality Report, rfc1989
+    LQR = 0xc025,
     /// Internet Protocol v4
     IPv4 = 0x0021,
     /// Internet Protocol v4 Control Protocol, rfc1332
@@ -40,6 +69,24 @@ pub enum Code {
     EchoReq = 9,
     EchoReply = 10,
     DiscardReq = 11,
+    /// RFC 1570
+    Identification = 12,
+    /// RFC 1570
+    TimeRemaining = 13,
+}
+
+/// Split a control packet, including the protocol field, into its Code, Identifier and
+/// data. The data ends where the Length field says, dropping any padding. Returns None if
+/// the packet is shorter than its header or its Length.
+pub fn parse_header(pkt: &[u8]) -> Option<(Code, u8, &[u8])> {
+    if pkt.len() < 6 {
+        return None;
+    }
+    let len = u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+    if len < 4 || len + 2 > pkt.len() {
+        return None;
+    }
+    Some((Code::from(pkt[2]), pkt[3], &pkt[6..len + 2]))
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -49,14 +96,30 @@ pub struct Packet<'a> {
 }
 
 impl<'a> Packet<'a> {
+    #[cfg(test)]
     pub fn buffer_len(&self) -> usize {
         2 + self.payload.buffer_len()
     }
 
+    #[cfg(test)]
     pub fn emit(&self, buffer: &mut [u8]) {
+        let mut pos = 0;
+        let res = self.emit_to(|data| {
+            buffer[pos..][..data.len()].copy_from_slice(data);
+            pos += data.len();
+            Ok::<_, core::convert::Infallible>(())
+        });
+        if let Err(e) = res {
+            match e {}
+        }
+    }
+
+    /// Emit the packet piece by piece, such as straight into a frame, stopping at the
+    /// first error.
+    pub fn emit_to<E>(&self, mut f: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
         let proto = self.proto as u16;
-        buffer[0..2].copy_from_slice(&proto.to_be_bytes());
-        self.payload.emit(&mut buffer[2..])
+        f(&proto.to_be_bytes())?;
+        self.payload.emit_to(&mut f)
     }
 }
 
@@ -67,6 +130,7 @@ pub enum Payload<'a> {
 }
 
 impl<'a> Payload<'a> {
+    #[cfg(test)]
     pub fn buffer_len(&self) -> usize {
         match self {
             Self::Raw(data) => data.len(),
@@ -74,15 +138,14 @@ impl<'a> Payload<'a> {
         }
     }
 
-    pub fn emit(&self, buffer: &mut [u8]) {
+    fn emit_to<E>(&self, f: &mut impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
         match self {
-            Self::Raw(data) => buffer.copy_from_slice(data),
+            Self::Raw(data) => f(data),
             Self::PPP(code, id, payload) => {
-                buffer[0] = *code as u8;
-                buffer[1] = *id;
                 let len = payload.buffer_len() as u16 + 4;
-                buffer[2..4].copy_from_slice(&len.to_be_bytes());
-                payload.emit(&mut buffer[4..])
+                let len = len.to_be_bytes();
+                f(&[*code as u8, *id, len[0], len[1]])?;
+                payload.emit_to(f)
             }
         }
     }
@@ -92,6 +155,8 @@ impl<'a> Payload<'a> {
 pub enum PPPPayload<'a> {
     Raw(&'a mut [u8]),
     PAP(&'a [u8], &'a [u8]),
+    /// CHAP Response: value and name.
+    CHAP(&'a [u8], &'a [u8]),
     Options(Options),
 }
 
@@ -100,77 +165,92 @@ impl<'a> PPPPayload<'a> {
         match self {
             Self::Raw(data) => data.len(),
             Self::PAP(user, pass) => 1 + user.len() + 1 + pass.len(),
+            Self::CHAP(value, name) => 1 + value.len() + name.len(),
             Self::Options(options) => options.buffer_len(),
         }
     }
 
-    pub fn emit(&self, buffer: &mut [u8]) {
+    fn emit_to<E>(&self, f: &mut impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E> {
         match self {
-            Self::Raw(data) => buffer.copy_from_slice(data),
+            Self::Raw(data) => f(data),
             Self::PAP(user, pass) => {
-                buffer[0] = user.len() as u8;
-                buffer[1..][..user.len()].copy_from_slice(user);
-                buffer[1 + user.len()] = pass.len() as u8;
-                buffer[1 + user.len() + 1..].copy_from_slice(pass);
+                f(&[user.len() as u8])?;
+                f(user)?;
+                f(&[pass.len() as u8])?;
+                f(pass)
             }
-            Self::Options(options) => options.emit(buffer),
+            Self::CHAP(value, name) => {
+                f(&[value.len() as u8])?;
+                f(value)?;
+                f(name)
+            }
+            Self::Options(options) => f(&options.0),
         }
     }
 }
 
-pub struct Options(pub Vec<OptionVal, MaxOptions>);
+/// Options of a Configure packet, encoded. The options echoed back from a received packet
+/// can be of any length, as long as the packet fits in a control packet.
+#[derive(Default)]
+pub struct Options(Vec<u8, MaxOptionsLen>);
 
 impl Options {
-    pub fn buffer_len(&self) -> usize {
-        self.0.iter().map(|opt| opt.buffer_len()).sum()
+    pub fn new() -> Self {
+        Self(Vec::new())
     }
 
-    pub fn emit(&self, mut buffer: &mut [u8]) {
-        for o in &self.0 {
-            let len = o.buffer_len();
-            o.emit(&mut buffer[..len]);
-            buffer = &mut buffer[len..];
+    /// Append an option. Fails if the packet would not fit in a control packet.
+    pub fn push(&mut self, code: u8, data: &[u8]) -> Result<(), MalformedError> {
+        if self.0.len() + 2 + data.len() > self.0.capacity() {
+            return Err(MalformedError::TooLong);
         }
+        unwrap!(self.0.extend_from_slice(&[code, data.len() as u8 + 2]));
+        unwrap!(self.0.extend_from_slice(data));
+        Ok(())
     }
-}
-
-#[cfg(feature = "defmt")]
-impl defmt::Format for Options {
-    fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "{=[?]}", &self.0[..])
-    }
-}
 
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct OptionVal {
-    code: u8,
-    data: OptionData,
-}
-
-impl OptionVal {
-    pub fn new(code: u8, data: &[u8]) -> Self {
-        Self {
-            code,
-            data: OptionData(unwrap!(Vec::from_slice(data))),
-        }
+    pub fn clear(&mut self) {
+        self.0.clear()
     }
 
     pub fn buffer_len(&self) -> usize {
-        2 + self.data.0.len()
+        self.0.len()
     }
 
     pub fn emit(&self, buffer: &mut [u8]) {
-        buffer[0] = self.code;
-        buffer[1] = self.data.0.len() as u8 + 2;
-        buffer[2..].copy_from_slice(&self.data.0);
+        buffer.copy_from_slice(&self.0)
     }
 }
 
-struct OptionData(Vec<u8, MaxOptionLen>);
-
 #[cfg(feature = "defmt")]
-impl defmt::Format for OptionData {
+impl defmt::Format for Options {
     fn format(&self, fmt: defmt::Formatter) {
-        defmt::write!(fmt, "{=[?]}", &self.0[..])
+        defmt::write!(fmt, "{=[u8]}", &self.0[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header_lengths() {
+        // Echo-Request with 4 bytes of data.
+        let pkt = [0xc0, 0x21, 9, 7, 0, 8, 1, 2, 3, 4];
+        assert_eq!(parse_header(&pkt), Some((Code::EchoReq, 7, &pkt[6..])));
+        let pkt = [0xc0, 0x21, 5, 1, 0, 4];
+        assert_eq!(parse_header(&pkt), Some((Code::TerminateReq, 1, &[][..])));
+
+        // Truncated header.
+        for len in 0..6 {
+            assert_eq!(parse_header(&pkt[..len]), None);
+        }
+        // Length shorter than the header.
+        assert_eq!(parse_header(&[0xc0, 0x21, 9, 7, 0, 3, 1]), None);
+        // Length longer than the packet.
+        assert_eq!(parse_header(&[0xc0, 0x21, 9, 7, 0, 9, 1, 2, 3, 4]), None);
+        // Length shorter than the packet, the padding is dropped.
+        let pkt = [0xc0, 0x21, 9, 7, 0, 6, 1, 2, 0, 0];
+        assert_eq!(parse_header(&pkt), Some((Code::EchoReq, 7, &pkt[6..8])));
     }
 }